@@ -0,0 +1,107 @@
+use crate::models::device::DeviceError;
+use crate::models::tag::TagValue;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+
+/// Entry of the audit log, one per command received.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AuditRecord {
+    pub timestamp: String,
+    pub topic: String,
+    pub client_id: Option<String>,
+    pub tag: Option<String>,
+    pub command: String,
+    pub value: Option<String>,
+    pub previous_value: Option<TagValue>,
+    pub success: bool,
+    pub error: Option<DeviceError>,
+    pub duration_ms: u64,
+}
+
+/// Append only log of JSON lines. When the file reaches `max_bytes` it is
+/// renamed to `{path}.1`, the older files are shifted and only `max_files`
+/// of them are kept.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: String,
+    max_bytes: u64,
+    max_files: u32,
+    file: Mutex<Option<File>>,
+}
+
+impl AuditLog {
+    /// An empty path disables the log.
+    pub fn new(path: &str, max_bytes: u64, max_files: u32) -> Self {
+        AuditLog {
+            path: path.to_string(),
+            max_bytes,
+            max_files,
+            file: Mutex::new(None),
+        }
+    }
+
+    pub fn write(&self, line: &str) -> std::io::Result<()> {
+        if self.path.is_empty() {
+            return Ok(());
+        }
+        let mut file = self.file.lock().unwrap();
+
+        let size = std::fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 >= self.max_bytes {
+            *file = None;
+            self.rotate()?;
+        }
+        if file.is_none() {
+            *file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+        match file.as_mut() {
+            Some(file) => writeln!(file, "{}", line),
+            None => Ok(()),
+        }
+    }
+
+    fn rotate(&self) -> std::io::Result<()> {
+        for number in (1..self.max_files).rev() {
+            let older = format!("{}.{}", self.path, number);
+            if std::path::Path::new(&older).exists() {
+                std::fs::rename(&older, format!("{}.{}", self.path, number + 1))?;
+            }
+        }
+        match self.max_files {
+            0 => std::fs::remove_file(&self.path),
+            _ => std::fs::rename(&self.path, format!("{}.1", self.path)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_audit_log_rotation() {
+        use super::AuditLog;
+
+        let folder = std::env::temp_dir().join(format!("audit_test_{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let path = folder.join("audit.log").to_str().unwrap().to_string();
+
+        let log = AuditLog::new(&path, 20, 2);
+        for line in ["first line", "second line", "third line", "fourth line"] {
+            log.write(line).unwrap();
+        }
+        let read = |path: String| std::fs::read_to_string(path).unwrap();
+        assert_eq!("fourth line\n", read(path.to_owned()));
+        assert_eq!("third line\n", read(format!("{}.1", path)));
+        assert_eq!("second line\n", read(format!("{}.2", path)));
+        assert!(!std::path::Path::new(&format!("{}.3", path)).exists());
+
+        AuditLog::new("", 20, 2).write("disabled").unwrap();
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+}
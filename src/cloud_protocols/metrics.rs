@@ -0,0 +1,36 @@
+use crate::gen_readable_struct;
+use crate::models::{cache, metrics};
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use tokio::net::TcpListener;
+
+pub const METRICS_FILE: &str = "metrics.ini";
+
+gen_readable_struct!(
+    struct MetricsConfig {
+        ip: std::net::IpAddr,
+        port: u16 = 9100,
+        tag_values: bool = false,
+    }
+);
+
+/// Serves the metrics of the gateway on `GET /metrics`.
+pub async fn serve(listener: TcpListener, tag_values: bool) -> std::io::Result<()> {
+    let app = Router::new().route(
+        "/metrics",
+        get(move || async move {
+            let values = match tag_values {
+                true => cache::all(),
+                false => Vec::new(),
+            };
+            (
+                [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                metrics::render(&values),
+            )
+                .into_response()
+        }),
+    );
+    axum::serve(listener, app).await
+}
@@ -1,11 +1,117 @@
+pub mod audit;
+pub mod metrics;
+pub mod modbus_server;
 pub mod mqtt;
+pub mod rest_api;
+pub mod topics;
 
 use crate::config_files::ini_parser;
-use mqtt::MqttIniConfig;
+use crate::device_protocols::DeviceProtocols;
+use metrics::{MetricsConfig, METRICS_FILE};
+use modbus_server::{ModbusServer, ModbusServerConnection, Register, MODBUS_SERVER_FOLDER};
+use mqtt::{CommandContext, MqttIniConfig, MQTT_FILE};
+use rest_api::{RestApiConfig, REST_API_FILE};
+use std::net::SocketAddr;
+use std::sync::Arc;
 
 pub fn get_mqtt_config() -> MqttIniConfig {
-    ini_parser::read_file::<MqttIniConfig>("mqtt.ini")
+    ini_parser::read_file::<MqttIniConfig>(MQTT_FILE)
         .into_iter()
         .next()
         .expect("Invalid mqtt.ini file")
 }
+
+// Error of a server that cannot listen on its address.
+fn bind_error(server: &str, socket_address: SocketAddr, err: std::io::Error) -> std::io::Error {
+    std::io::Error::new(
+        err.kind(),
+        format!(
+            "The {} cannot listen on {}: {}",
+            server, socket_address, err
+        ),
+    )
+}
+
+// Listener of an HTTP server, bound before spawning it so a busy or privileged
+// address stops the gateway at startup.
+fn bind(server: &str, socket_address: SocketAddr) -> std::io::Result<tokio::net::TcpListener> {
+    let listener = std::net::TcpListener::bind(socket_address)
+        .map_err(|err| bind_error(server, socket_address, err))?;
+    listener.set_nonblocking(true)?;
+    tokio::net::TcpListener::from_std(listener)
+}
+
+/// Starts the Modbus TCP server when the modbus_server folder is configured,
+/// failing when its address cannot be bound.
+pub fn start_modbus_server(devices: Arc<Vec<DeviceProtocols>>) -> std::io::Result<()> {
+    let connection_file = format!("{}/connection.ini", MODBUS_SERVER_FOLDER);
+    if !std::path::Path::new(&connection_file).exists() {
+        return Ok(());
+    }
+
+    let connection = ini_parser::read_file::<ModbusServerConnection>(&connection_file)
+        .into_iter()
+        .next()
+        .expect("Invalid modbus server connection.ini file");
+    let registers =
+        ini_parser::read_file::<Register>(&format!("{}/registers.ini", MODBUS_SERVER_FOLDER));
+
+    // The server of tokio-modbus binds its own listener, so the address is
+    // checked before, when the gateway can still stop.
+    let socket_address = SocketAddr::new(connection.ip, connection.port);
+    std::net::TcpListener::bind(socket_address)
+        .map_err(|err| bind_error("Modbus TCP server", socket_address, err))?;
+    let server = ModbusServer::new(devices, registers);
+    tokio::spawn(async move {
+        if let Err(err) = modbus_server::serve(socket_address, server).await {
+            tracing::error!(error = %err, "The Modbus TCP server has stopped");
+        }
+    });
+    Ok(())
+}
+
+/// Starts the HTTP server of the Prometheus metrics when metrics.ini exists,
+/// failing when its address cannot be bound.
+pub fn start_metrics_server() -> std::io::Result<()> {
+    if !std::path::Path::new(METRICS_FILE).exists() {
+        return Ok(());
+    }
+
+    let config = ini_parser::read_file::<MetricsConfig>(METRICS_FILE)
+        .into_iter()
+        .next()
+        .expect("Invalid metrics.ini file");
+
+    let listener = bind("metrics server", SocketAddr::new(config.ip, config.port))?;
+    tokio::spawn(async move {
+        if let Err(err) = metrics::serve(listener, config.tag_values).await {
+            tracing::error!(error = %err, "The metrics server has stopped");
+        }
+    });
+    Ok(())
+}
+
+/// Starts the REST API when rest_api.ini exists, failing when its address
+/// cannot be bound.
+pub fn start_rest_api(context: Arc<CommandContext>) -> std::io::Result<()> {
+    if !std::path::Path::new(REST_API_FILE).exists() {
+        return Ok(());
+    }
+
+    let config = ini_parser::read_file::<RestApiConfig>(REST_API_FILE)
+        .into_iter()
+        .next()
+        .expect("Invalid rest_api.ini file");
+    assert!(
+        !config.token.is_empty(),
+        "The token of the rest_api.ini file cannot be empty"
+    );
+
+    let listener = bind("REST API", SocketAddr::new(config.ip, config.port))?;
+    tokio::spawn(async move {
+        if let Err(err) = rest_api::serve(listener, config.token, context).await {
+            tracing::error!(error = %err, "The REST API has stopped");
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,372 @@
+use crate::device_protocols::modbus::shared::{self, Command};
+use crate::device_protocols::DeviceProtocols;
+use crate::gen_readable_struct;
+use crate::models::cache;
+use crate::models::device::DeviceError;
+use crate::models::tag::TagValue;
+use futures::FutureExt;
+use std::future::Future;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio_modbus::prelude::{Request, Response};
+use tokio_modbus::server::{tcp::Server, Service};
+
+pub const MODBUS_SERVER_FOLDER: &str = "modbus_server";
+
+gen_readable_struct!(
+    struct ModbusServerConnection {
+        ip: std::net::IpAddr,
+        port: u16,
+    }
+);
+
+gen_readable_struct!(
+    struct Register {
+        tag: String,
+        address: u16,
+        length: u16 = 2,
+        command: shared::Command = shared::Command::Holding,
+        swap: shared::Swap = shared::Swap::BigEndian,
+        data_type: shared::Type = shared::Type::Float,
+        multiplier: f32 = 1.0,
+    }
+);
+
+impl Register {
+    fn words(&self) -> u16 {
+        match self.command {
+            Command::Coil | Command::Discrete => 1,
+            Command::Holding | Command::Input => self.length,
+        }
+    }
+
+    fn encode(&self, value: &TagValue) -> Vec<u16> {
+        let value = value.to_f32() * self.multiplier;
+        match self.command {
+            Command::Coil | Command::Discrete => vec![(value != 0.0) as u16],
+            Command::Holding | Command::Input => {
+                shared::to_registers(value as f64, &self.swap, &self.data_type, self.length)
+            }
+        }
+    }
+
+    fn decode(&self, words: &[u16]) -> Result<TagValue, DeviceError> {
+        match self.command {
+            Command::Coil | Command::Discrete => Ok(TagValue::I32(words[0] as i32)),
+            Command::Holding | Command::Input => shared::parse_readed(
+                words.to_vec(),
+                &self.swap,
+                &self.data_type,
+                &(1.0 / self.multiplier),
+            ),
+        }
+    }
+}
+
+type ServerResponse = Pin<Box<dyn Future<Output = Result<Response, Error>> + Send + Sync>>;
+
+/// Modbus TCP slave that exposes the last value read of the mapped tags and
+/// forwards the writes of the mapped registers to the devices.
+#[derive(Clone)]
+pub struct ModbusServer {
+    devices: Arc<Vec<DeviceProtocols>>,
+    registers: Arc<Vec<Register>>,
+}
+
+impl ModbusServer {
+    pub fn new(devices: Arc<Vec<DeviceProtocols>>, registers: Vec<Register>) -> Self {
+        ModbusServer {
+            devices,
+            registers: Arc::new(registers),
+        }
+    }
+
+    fn read(&self, command: Command, address: u16, quantity: u16) -> Vec<u16> {
+        let (first, last) = (address as usize, address as usize + quantity as usize);
+        let mut words = vec![0; quantity as usize];
+
+        for register in self.registers.iter().filter(|r| r.command == command) {
+            let value = match cache::get(&register.tag) {
+                Some(value) => value,
+                None => continue,
+            };
+            for (offset, word) in register.encode(&value).into_iter().enumerate() {
+                let word_address = register.address as usize + offset;
+                if word_address >= first && word_address < last {
+                    words[word_address - first] = word;
+                }
+            }
+        }
+        words
+    }
+
+    fn write(
+        &self,
+        function: u8,
+        command: Command,
+        address: u16,
+        words: Vec<u16>,
+        response: Response,
+    ) -> ServerResponse {
+        let (first, last) = (address as usize, address as usize + words.len());
+
+        let mut writes = Vec::new();
+        for register in self.registers.iter().filter(|r| r.command == command) {
+            let register_first = register.address as usize;
+            let register_last = register_first + register.words() as usize;
+            if register_first < first || register_last > last {
+                continue;
+            }
+            let value = match register.decode(&words[register_first - first..register_last - first])
+            {
+                Ok(value) => value,
+                Err(err) => return ready(exception(function, ILLEGAL_DATA_VALUE, err.to_string())),
+            };
+            match self.devices.iter().find(|d| d.id() == register.tag) {
+                Some(dev) => writes.push((dev.to_owned(), value)),
+                None => {
+                    let msg = format!("The tag {} cannot be found.", register.tag);
+                    return ready(exception(function, ILLEGAL_DATA_ADDRESS, msg));
+                }
+            }
+        }
+        if writes.is_empty() {
+            let msg = format!("There is no tag mapped at address {}.", address);
+            return ready(exception(function, ILLEGAL_DATA_ADDRESS, msg));
+        }
+
+        // The device futures are not Sync, so they run in their own task.
+        let task = tokio::spawn(async move {
+            for (dev, value) in writes {
+                if let Err(err) = dev.write(value).await {
+                    return exception(function, SERVER_DEVICE_FAILURE, err.to_string());
+                }
+            }
+            response
+        });
+        Box::pin(task.map(|result| result.map_err(Error::other)))
+    }
+}
+
+// Exception codes of the rejected requests.
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+const SERVER_DEVICE_FAILURE: u8 = 0x04;
+
+// The server of tokio-modbus closes the connection when the service fails, so
+// the exceptions are answered as a custom response with the function code plus
+// 0x80, which is encoded exactly as an exception response.
+fn exception(function: u8, code: u8, msg: String) -> Response {
+    tracing::warn!(function, exception = code, "{}", msg);
+    Response::Custom(function | 0x80, vec![code])
+}
+
+fn ready(response: Response) -> ServerResponse {
+    Box::pin(futures::future::ready(Ok(response)))
+}
+
+fn to_coils(words: Vec<u16>) -> Vec<bool> {
+    words.into_iter().map(|w| w != 0).collect()
+}
+
+impl Service for ModbusServer {
+    type Request = Request;
+    type Response = Response;
+    type Error = Error;
+    type Future = ServerResponse;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let response = match req {
+            Request::ReadCoils(address, quantity) => {
+                Response::ReadCoils(to_coils(self.read(Command::Coil, address, quantity)))
+            }
+            Request::ReadDiscreteInputs(address, quantity) => Response::ReadDiscreteInputs(
+                to_coils(self.read(Command::Discrete, address, quantity)),
+            ),
+            Request::ReadHoldingRegisters(address, quantity) => {
+                Response::ReadHoldingRegisters(self.read(Command::Holding, address, quantity))
+            }
+            Request::ReadInputRegisters(address, quantity) => {
+                Response::ReadInputRegisters(self.read(Command::Input, address, quantity))
+            }
+            Request::WriteSingleCoil(address, coil) => {
+                let response = Response::WriteSingleCoil(address, coil);
+                return self.write(0x05, Command::Coil, address, vec![coil as u16], response);
+            }
+            Request::WriteMultipleCoils(address, coils) => {
+                let response = Response::WriteMultipleCoils(address, coils.len() as u16);
+                let words = coils.into_iter().map(|c| c as u16).collect();
+                return self.write(0x0F, Command::Coil, address, words, response);
+            }
+            Request::WriteSingleRegister(address, word) => {
+                let response = Response::WriteSingleRegister(address, word);
+                return self.write(0x06, Command::Holding, address, vec![word], response);
+            }
+            Request::WriteMultipleRegisters(address, words) => {
+                let response = Response::WriteMultipleRegisters(address, words.len() as u16);
+                return self.write(0x10, Command::Holding, address, words, response);
+            }
+            Request::ReadWriteMultipleRegisters(..) => {
+                let msg = "The request ReadWriteMultipleRegisters is not supported.".to_string();
+                exception(0x17, ILLEGAL_FUNCTION, msg)
+            }
+            Request::Custom(function, _) => {
+                let msg = format!("The function {} is not supported.", function);
+                exception(function, ILLEGAL_FUNCTION, msg)
+            }
+            Request::Disconnect => {
+                return Box::pin(futures::future::ready(Err(Error::other("Disconnected."))))
+            }
+        };
+        ready(response)
+    }
+}
+
+pub async fn serve(socket_address: SocketAddr, server: ModbusServer) -> Result<(), std::io::Error> {
+    Server::new(socket_address)
+        .serve(move || Ok(server.to_owned()))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serve, ModbusServer, Register};
+    use crate::device_protocols::bus::Bus;
+    use crate::device_protocols::health::Breaker;
+    use crate::device_protocols::modbus::shared::{Command, Swap, Type};
+    use crate::device_protocols::{modbus, DeviceProtocols, Mode};
+    use crate::models::cache;
+    use crate::models::tag::{TagResponse, TagValue};
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio_modbus::client::Context;
+    use tokio_modbus::prelude::*;
+    use tokio_modbus::server::Service;
+
+    fn free_address() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    async fn connect(address: SocketAddr) -> Context {
+        for _ in 0..50 {
+            if let Ok(ctx) = tcp::connect(address).await {
+                return ctx;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("The server at {} is not listening.", address);
+    }
+
+    fn register(tag: &str, address: u16, data_type: Type, multiplier: f32) -> Register {
+        Register {
+            tag: tag.to_string(),
+            address,
+            length: 2,
+            command: Command::Holding,
+            swap: Swap::BigEndian,
+            data_type,
+            multiplier,
+        }
+    }
+
+    // Device that stores the registers written on it.
+    #[derive(Clone)]
+    struct FakeDevice(Arc<Mutex<Vec<u16>>>);
+
+    impl Service for FakeDevice {
+        type Request = Request;
+        type Response = Response;
+        type Error = std::io::Error;
+        type Future = futures::future::Ready<Result<Response, std::io::Error>>;
+
+        fn call(&self, req: Self::Request) -> Self::Future {
+            match req {
+                Request::WriteMultipleRegisters(address, words) => {
+                    let quantity = words.len() as u16;
+                    *self.0.lock().unwrap() = words;
+                    futures::future::ready(Ok(Response::WriteMultipleRegisters(address, quantity)))
+                }
+                _ => futures::future::ready(Ok(Response::ReadHoldingRegisters(vec![]))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_modbus_server() {
+        let fake_address = free_address();
+        let fake_device = FakeDevice(Arc::new(Mutex::new(Vec::new())));
+        let written = fake_device.0.clone();
+        tokio::spawn(async move {
+            tokio_modbus::server::tcp::Server::new(fake_address)
+                .serve(move || Ok(fake_device.to_owned()))
+                .await
+        });
+
+        let connection = modbus::tcp::Connection::for_test("server_test", fake_address);
+        let tag = modbus::tcp::Tag {
+            mode: Mode::Write,
+            ..modbus::tcp::Tag::for_test("Setpoint")
+        };
+        let bus = Arc::new(Bus::new(1, Duration::ZERO));
+        let breaker = Arc::new(Breaker::new(3, Duration::from_secs(1)));
+        let devices = Arc::new(vec![DeviceProtocols::ModbusTCP(
+            bus, breaker, connection, tag,
+        )]);
+
+        cache::update(
+            &TagResponse {
+                id: "server_test/Tension".to_string(),
+                value: TagValue::F32(230.5),
+                cached: false,
+                timestamp: None,
+            },
+            Duration::MAX,
+        );
+        cache::update(
+            &TagResponse {
+                id: "server_test/Energia".to_string(),
+                value: TagValue::I32(70000),
+                cached: false,
+                timestamp: None,
+            },
+            Duration::MAX,
+        );
+        let registers = vec![
+            register("server_test/Tension", 0, Type::Float, 1.0),
+            register("server_test/Energia", 2, Type::Integer, 1.0),
+            register("server_test/Setpoint", 10, Type::Integer, 1.0),
+        ];
+
+        let address = free_address();
+        tokio::spawn(serve(address, ModbusServer::new(devices, registers)));
+        let mut ctx = connect(address).await;
+
+        let words = ctx.read_holding_registers(0, 5).await.unwrap();
+        let tension = 230.5f32.to_bits();
+        assert_eq!(
+            vec![(tension >> 16) as u16, tension as u16, 1, 4464, 0],
+            words
+        );
+
+        ctx.write_multiple_registers(10, &[0, 42]).await.unwrap();
+        assert_eq!(Some(&42), written.lock().unwrap().last());
+
+        // The rejected requests are answered with an exception on the same connection.
+        let err = ctx
+            .write_multiple_registers(20, &[0, 42])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Illegal data address"), "{}", err);
+        let err = ctx
+            .read_write_multiple_registers(0, 1, 10, &[0])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Illegal function"), "{}", err);
+        assert_eq!(vec![1], ctx.read_holding_registers(2, 1).await.unwrap());
+    }
+}
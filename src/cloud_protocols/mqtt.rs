@@ -1,14 +1,26 @@
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use super::audit::{AuditLog, AuditRecord};
 use super::get_mqtt_config;
+use super::rest_api::REST_API_FILE;
+use super::topics::{PayloadShape, Placeholders, TopicLayout, Topics};
+use crate::config_files::ini_parser;
+use crate::device_protocols::bus::Priority;
 use crate::device_protocols::DeviceProtocols;
-use crate::models::tag::TagValue;
+use crate::models::cache;
+use crate::models::device::DeviceError;
+use crate::models::history;
+use crate::models::metrics;
 use crate::{gen_matcher, gen_readable_struct};
+use chrono::{DateTime, Utc};
 use gmqtt_client::{Message, MqttClient, MqttClientBuilder, QoS};
-use serde_json;
+use tokio::time::timeout;
+use tracing::Instrument;
 use url::Url;
 
 gen_matcher!(
+    #[allow(clippy::enum_variant_names)]
     enum MqttQoS {
         AtMostOnce,
         AtLeastOnce,
@@ -27,6 +39,7 @@ impl MqttQoS {
 }
 
 gen_matcher!(
+    #[allow(clippy::upper_case_acronyms)]
     enum MqttProtocol {
         TCP,
         UDP,
@@ -50,6 +63,27 @@ gen_readable_struct!(
         port: u32,
         qos: MqttQoS,
         mqtt_topic_installation_prefix: String,
+        audit_publish: bool = false,
+        topic_layout: TopicLayout = TopicLayout::Device,
+        topic_template: String = "{prefix}/{class}/{device}/{tag}".to_string(),
+        topic_placeholders: Placeholders = Placeholders::default(),
+        payload: PayloadShape = PayloadShape::Json,
+        retain_measures: bool = false,
+        retain_aggregates: bool = false,
+        retain_status: bool = false,
+    }
+);
+
+pub const MQTT_FILE: &str = "mqtt.ini";
+
+// Settings of the commands, read from mqtt.ini or, without MQTT, from
+// rest_api.ini.
+gen_readable_struct!(
+    struct CommandConfig {
+        command_deadline_ms: u64 = 10000,
+        audit_file: String = "audit.log".to_string(),
+        audit_max_bytes: u64 = 10485760,
+        audit_max_files: u32 = 5,
     }
 );
 
@@ -64,62 +98,275 @@ impl std::fmt::Display for MqttError {
     }
 }
 
-async fn process_recv_mqtt_command(
-    client: MqttClient,
-    msg: Message,
-    devices: Arc<Vec<DeviceProtocols>>,
-) {
-    let payload = msg.payload_str().into_owned();
-    let recv_tag_name = msg.topic().rsplit('/').next().unwrap();
+/// State shared by the handlers of the received commands.
+pub struct CommandContext {
+    pub devices: Arc<Vec<DeviceProtocols>>,
+    pub deadline: Duration,
+    pub audit: AuditLog,
+    /// Topic where the audit records are also published, if any.
+    pub audit_topic: Option<String>,
+}
+
+async fn process_recv_mqtt_command<F>(
+    topic: String,
+    payload: String,
+    client_id: Option<String>,
+    context: Arc<CommandContext>,
+    reply: F,
+) where
+    F: Fn(&str, &str) -> Result<(), MqttError>,
+{
+    let span = tracing::info_span!("mqtt_command", topic = %topic, client_id = ?client_id);
+    handle_command(topic, payload, client_id, context, reply)
+        .instrument(span)
+        .await
+}
+
+async fn handle_command<F>(
+    topic: String,
+    payload: String,
+    client_id: Option<String>,
+    context: Arc<CommandContext>,
+    reply: F,
+) where
+    F: Fn(&str, &str) -> Result<(), MqttError>,
+{
+    let started = Instant::now();
+    let mut record = AuditRecord {
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        topic: topic.to_owned(),
+        client_id,
+        ..Default::default()
+    };
 
-    let splitted_payload = payload.split(' ').collect::<Vec<&str>>();
-    let dev = devices.iter().find(|d| d.tag_name() == recv_tag_name);
+    let topic_to_sent = topic.replace("/commands", "");
+    let response = run_command(&topic, &payload, &context, &mut record).await;
+    record.duration_ms = started.elapsed().as_millis() as u64;
+    tracing::info!(
+        command = %record.command,
+        value = ?record.value,
+        success = record.success,
+        duration_ms = record.duration_ms,
+        "Command processed"
+    );
 
-    if dev.is_none() {
-        return;
+    if let Err(err) = reply(&topic_to_sent, &response) {
+        tracing::error!(error = %err, "The response cannot be sent");
     }
 
-    let dev = dev.unwrap();
-    let topic_to_sent = msg.topic().replace("/commands", "");
+    let line = audit(&context, &record);
+    if let Some(audit_topic) = &context.audit_topic {
+        if let Err(err) = reply(audit_topic, &line) {
+            tracing::error!(error = %err, "The audit record cannot be sent");
+        }
+    }
+}
+
+async fn run_command(
+    topic: &str,
+    payload: &str,
+    context: &CommandContext,
+    record: &mut AuditRecord,
+) -> String {
+    // The topic ends with the device and the tag, the same tag name can be in
+    // several devices (i.e. with a profile).
+    let mut levels = topic.rsplit('/');
+    let (tag, device) = (
+        levels.next().unwrap_or_default(),
+        levels.next().unwrap_or_default(),
+    );
+    let id = format!("{}/{}", device, tag);
+    match context.devices.iter().find(|d| d.id() == id) {
+        Some(dev) => execute(dev, payload, context, record).await,
+        None => {
+            parse_command(payload, record);
+            let msg = format!("The tag {} cannot be found.", id);
+            failed(record, DeviceError::InvalidCommand(msg))
+        }
+    }
+}
 
-    match splitted_payload.as_slice() {
+// Splits the payload in words, keeping the command and its value in the record.
+fn parse_command<'a>(payload: &'a str, record: &mut AuditRecord) -> Vec<&'a str> {
+    let words = payload.split_whitespace().collect::<Vec<&str>>();
+    record.command = words.first().map(|w| w.to_string()).unwrap_or_default();
+    record.value = words.get(1).map(|v| v.to_string());
+    words
+}
+
+/// Runs the command of the payload (`PING`, `READ [force]`, `HISTORY from [to]`,
+/// `SELECT value` or `WRITE value`) on the tag, returning the JSON response.
+pub async fn execute(
+    dev: &DeviceProtocols,
+    payload: &str,
+    context: &CommandContext,
+    record: &mut AuditRecord,
+) -> String {
+    let deadline = context.deadline;
+    let words = parse_command(payload, record);
+    record.tag = Some(dev.id());
+
+    let (response, result) = match words.as_slice() {
         ["PING"] => {
-            let result = dev.read().await;
-            if result.is_ok() {
-                send_message(&client, &topic_to_sent, "PONG").unwrap();
-            } else {
-                send_message(&client, &topic_to_sent, "Error").unwrap();
-            }
+            let result = timeout(deadline, dev.read(Priority::Command))
+                .await
+                .unwrap_or(Err(DeviceError::Timeout(deadline)));
+            let response = match &result {
+                Ok(_) => "PONG".to_string(),
+                Err(err) => to_json(err),
+            };
+            (response, result.map(|_| ()))
+        }
+        ["READ"] | ["READ", "force"] => {
+            let force = words.len() > 1;
+            let result = timeout(deadline, dev.read_cached(Priority::Command, force))
+                .await
+                .unwrap_or(Err(DeviceError::Timeout(deadline)));
+            (to_json(&result), result.map(|_| ()))
+        }
+        ["HISTORY", from] | ["HISTORY", from, _] => {
+            let to = words.get(2).copied();
+            let (from, to) = match parse_time_range(from, to) {
+                Ok(range) => range,
+                Err(err) => return failed(record, err),
+            };
+            let result = history::query(dev.id(), from, to).await;
+            (to_json(&result), result.map(|_| ()))
         }
-        ["READ"] => {
-            let result = dev.read().await;
-            let json = serde_json::to_string(&result).unwrap();
-            send_message(&client, &topic_to_sent, &json).unwrap();
+        ["SELECT", value] => {
+            let result = dev
+                .parse_value(value)
+                .and_then(|t_value| dev.select(t_value));
+            (to_json(&result), result)
         }
         ["WRITE", value] => {
-            let t_value = TagValue::I32(value.parse().unwrap());
-            let result = dev.write(t_value).await;
-            let json = serde_json::to_string(&result).unwrap();
-            send_message(&client, &topic_to_sent, &json).unwrap();
-            println!("WRITE VALUE: {} COMMAND", value);
+            let t_value = match dev.parse_value(value) {
+                Ok(t_value) => t_value,
+                Err(err) => return failed(record, err),
+            };
+            // The previous value is read once the write is allowed, so a
+            // rejected write never reaches the device. When it cannot be read
+            // the audit log keeps the last value polled.
+            let previous_value = &mut record.previous_value;
+            let request = async {
+                dev.authorize_write(&t_value)?;
+                *previous_value = match dev.read(Priority::Command).await {
+                    Ok(response) => Some(response.value),
+                    Err(_) => cache::get(&dev.id()),
+                };
+                dev.write_authorized(t_value).await
+            };
+            let result = timeout(deadline, request)
+                .await
+                .unwrap_or(Err(DeviceError::Timeout(deadline)));
+            (to_json(&result), result)
         }
         _ => {
-            println!("Invalid Command!");
+            let msg = format!("Invalid command \"{}\".", payload);
+            return failed(record, DeviceError::InvalidCommand(msg));
         }
     };
+
+    record.success = result.is_ok();
+    record.error = result.err();
+    response
+}
+
+// Range of a HISTORY command, in RFC 3339 times. Without `to` it ends now.
+fn parse_time_range(
+    from: &str,
+    to: Option<&str>,
+) -> Result<(DateTime<Utc>, DateTime<Utc>), DeviceError> {
+    let parse = |time: &str| {
+        DateTime::parse_from_rfc3339(time)
+            .map(|time| time.with_timezone(&Utc))
+            .map_err(|err| {
+                DeviceError::InvalidCommand(format!("Invalid time \"{}\": {}.", time, err))
+            })
+    };
+    let to = match to {
+        Some(to) => parse(to)?,
+        None => Utc::now(),
+    };
+    Ok((parse(from)?, to))
+}
+
+/// Writes the record in the audit log, returning it as a JSON line.
+pub fn audit(context: &CommandContext, record: &AuditRecord) -> String {
+    let line = to_json(record);
+    if let Err(err) = context.audit.write(&line) {
+        tracing::error!(error = %err, "The audit record cannot be written");
+    }
+    line
+}
+
+fn failed(record: &mut AuditRecord, err: DeviceError) -> String {
+    record.error = Some(err.to_owned());
+    to_json(&Err::<(), _>(err))
+}
+
+// The responses are plain data, so their serialization cannot fail.
+fn to_json<T: serde::Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_default()
 }
 
-pub fn send_message(client: &MqttClient, topic: &str, msg: &str) -> Result<(), MqttError> {
-    client
-        .publish_json(topic, msg, false, QoS::AtLeastOnce, None)
-        .map_err(|err| MqttError(err.to_string()))?;
+pub fn send_message(
+    client: &MqttClient,
+    topic: &str,
+    msg: &str,
+    retain: bool,
+) -> Result<(), MqttError> {
+    let result = client.publish_json(topic, msg, retain, QoS::AtLeastOnce, None);
+    metrics::record_publish(result.is_ok(), client.tx_pending());
+    result.map_err(|err| MqttError(err.to_string()))?;
 
     Ok(())
 }
 
+/// Context of the commands received by MQTT or by the REST API, with the
+/// deadline and the audit log configured in mqtt.ini. Without mqtt.ini they
+/// are taken from rest_api.ini, so the REST API works without a broker.
+pub fn command_context(devices: Arc<Vec<DeviceProtocols>>) -> Arc<CommandContext> {
+    let mqtt = std::path::Path::new(MQTT_FILE).exists();
+    let file = match mqtt {
+        true => MQTT_FILE,
+        false => REST_API_FILE,
+    };
+    let config = match std::path::Path::new(file).exists() {
+        true => ini_parser::read_file::<CommandConfig>(file)
+            .into_iter()
+            .next(),
+        false => CommandConfig::try_from(std::collections::HashMap::new()).ok(),
+    }
+    .unwrap_or_else(|| panic!("Invalid {} file", file));
+    let audit_topic = match mqtt {
+        true => {
+            let mqtt_config = get_mqtt_config();
+            match mqtt_config.audit_publish {
+                true => Some(format!(
+                    "{}/audit",
+                    mqtt_config.mqtt_topic_installation_prefix
+                )),
+                false => None,
+            }
+        }
+        false => None,
+    };
+    Arc::new(CommandContext {
+        devices,
+        deadline: Duration::from_millis(config.command_deadline_ms),
+        audit: AuditLog::new(
+            &config.audit_file,
+            config.audit_max_bytes,
+            config.audit_max_files,
+        ),
+        audit_topic,
+    })
+}
+
 pub fn connect_broker_subscribing_to_commands(
-    devices: Arc<Vec<DeviceProtocols>>,
-) -> Result<(MqttClient, String), MqttError> {
+    context: Arc<CommandContext>,
+) -> Result<(MqttClient, Topics), MqttError> {
     let mqtt_config = get_mqtt_config();
 
     let protocol = mqtt_config.protocol.to_string();
@@ -135,14 +382,363 @@ pub fn connect_broker_subscribing_to_commands(
 
     let callback_mqtt_client = mqtt_client.clone();
     mqtt_client.set_on_message_callback(move |msg: &Message| {
+        let client = callback_mqtt_client.to_owned();
+        // MQTT 5 clients can identify themselves with a user property.
+        let client_id = msg
+            .properties()
+            .user_properties
+            .iter()
+            .find(|(key, _)| key == "client_id")
+            .map(|(_, value)| value.to_owned());
         tokio::spawn(process_recv_mqtt_command(
-            callback_mqtt_client.to_owned(),
-            msg.to_owned(),
-            devices.to_owned(),
+            msg.topic().to_string(),
+            msg.payload_str().into_owned(),
+            client_id,
+            context.to_owned(),
+            move |topic: &str, msg: &str| send_message(&client, topic, msg, false),
         ));
     });
 
     tokio::spawn(mqtt_worker.run());
 
-    Ok((mqtt_client, mqtt_config.mqtt_topic_installation_prefix))
+    let topics = Topics::new(&mqtt_config).map_err(MqttError)?;
+    Ok((mqtt_client, topics))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{process_recv_mqtt_command, CommandContext, MqttError};
+    use crate::cloud_protocols::audit::AuditLog;
+    use crate::device_protocols::bus::Bus;
+    use crate::device_protocols::health::Breaker;
+    use crate::device_protocols::modbus::shared::Command;
+    use crate::device_protocols::{modbus, DeviceProtocols, Mode};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    // Device behind a closed port, so every request fails.
+    pub(crate) fn unreachable_device(
+        tag_name: &str,
+        command: Command,
+        mode: Mode,
+    ) -> DeviceProtocols {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let connection = modbus::tcp::Connection {
+            breaker_failures: 100,
+            ..modbus::tcp::Connection::for_test("mqtt_test", address)
+        };
+        let tag = modbus::tcp::Tag {
+            command,
+            mode,
+            ..modbus::tcp::Tag::for_test(tag_name)
+        };
+        let bus = Arc::new(Bus::new(1, Duration::ZERO));
+        let breaker = Arc::new(Breaker::new(100, Duration::from_secs(1)));
+        DeviceProtocols::ModbusTCP(bus, breaker, connection, tag)
+    }
+
+    pub(crate) fn context(devices: Vec<DeviceProtocols>) -> Arc<CommandContext> {
+        Arc::new(CommandContext {
+            devices: Arc::new(devices),
+            deadline: Duration::from_secs(2),
+            audit: AuditLog::new("", 0, 0),
+            audit_topic: Some("plant/audit".to_string()),
+        })
+    }
+
+    // Returns the response and the audit record of the command.
+    async fn command(
+        topic: &str,
+        payload: &str,
+    ) -> ((String, serde_json::Value), serde_json::Value) {
+        let devices = vec![
+            unreachable_device("Setpoint", Command::Holding, Mode::Write),
+            unreachable_device("Power", Command::Input, Mode::Write),
+            unreachable_device("Energy", Command::Holding, Mode::Read),
+            unreachable_device("Pump", Command::Coil, Mode::Write),
+        ];
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let replies = sent.clone();
+        process_recv_mqtt_command(
+            topic.to_string(),
+            payload.to_string(),
+            Some("scada".to_string()),
+            context(devices),
+            move |topic: &str, msg: &str| {
+                replies
+                    .lock()
+                    .unwrap()
+                    .push((topic.to_string(), msg.to_string()));
+                Ok(())
+            },
+        )
+        .await;
+
+        let mut sent = sent.lock().unwrap();
+        let (audit_topic, audit) = sent.pop().unwrap();
+        assert_eq!("plant/audit", audit_topic);
+        let (topic, msg) = sent.pop().unwrap();
+        (
+            (topic, serde_json::from_str(&msg).unwrap()),
+            serde_json::from_str(&audit).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_garbage_commands() {
+        let code = |((_, json), _): ((String, serde_json::Value), serde_json::Value)| {
+            json["Err"]["code"].to_owned()
+        };
+        let topic = "plant/commands/mqtt_test/Setpoint";
+
+        let ((reply_topic, _), _) = command(topic, "READ").await;
+        assert_eq!("plant/mqtt_test/Setpoint", reply_topic);
+
+        let (_, audit) = command(topic, "WRITE 12").await;
+        assert_eq!("scada", audit["client_id"]);
+        assert_eq!("mqtt_test/Setpoint", audit["tag"]);
+        assert_eq!("WRITE", audit["command"]);
+        assert_eq!("12", audit["value"]);
+        assert_eq!(serde_json::Value::Null, audit["previous_value"]);
+        assert_eq!(false, audit["success"]);
+        assert_eq!("transport", audit["error"]["code"]);
+
+        assert_eq!("invalid_command", code(command(topic, "").await));
+        assert_eq!("invalid_command", code(command(topic, "DROP TABLE").await));
+        assert_eq!("invalid_command", code(command(topic, "WRITE").await));
+        assert_eq!("invalid_command", code(command(topic, "WRITE abc").await));
+        assert_eq!("invalid_command", code(command(topic, "WRITE 1 2").await));
+        assert_eq!("invalid_command", code(command(topic, "\u{0}\u{ff}").await));
+        assert_eq!(
+            "invalid_command",
+            code(command("plant/commands/mqtt_test/Unknown", "READ").await)
+        );
+        assert_eq!("transport", code(command(topic, "READ").await));
+        assert_eq!("transport", code(command(topic, "READ force").await));
+        assert_eq!("invalid_command", code(command(topic, "READ now").await));
+        assert_eq!(
+            "invalid_command",
+            code(command(topic, "HISTORY yesterday").await)
+        );
+        // The history is not enabled without history.ini.
+        let history = "HISTORY 2024-01-01T00:00:00Z 2024-01-02T00:00:00+01:00";
+        assert_eq!("config", code(command(topic, history).await));
+        assert_eq!("transport", code(command(topic, "WRITE 12").await));
+        assert_eq!(
+            "config",
+            code(command("plant/commands/mqtt_test/Power", "WRITE 12").await)
+        );
+        assert_eq!(
+            "config",
+            code(command("plant/commands/mqtt_test/Energy", "WRITE 12").await)
+        );
+        assert_eq!("invalid_command", code(command(topic, "WRITE 1e12").await));
+        assert_eq!("transport", code(command(topic, "WRITE 21.5").await));
+
+        let pump = "plant/commands/mqtt_test/Pump";
+        assert_eq!("transport", code(command(pump, "WRITE on").await));
+        assert_eq!("invalid_command", code(command(pump, "WRITE 21.5").await));
+
+        // A broker error must not panic the command task.
+        process_recv_mqtt_command(
+            topic.to_string(),
+            "READ".to_string(),
+            None,
+            context(vec![]),
+            |_: &str, _: &str| Err(MqttError("Broker disconnected.".to_string())),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_same_tag_in_several_devices() {
+        use super::run_command;
+        use crate::cloud_protocols::audit::AuditRecord;
+
+        // Two devices with the same profile, only the second one is writable.
+        let device = |name: &str, mode: Mode| {
+            let mut dev = unreachable_device("Power", Command::Holding, mode);
+            if let DeviceProtocols::ModbusTCP(_, _, connection, _) = &mut dev {
+                connection.name = name.to_string();
+            }
+            dev
+        };
+        let context = context(vec![
+            device("meter1", Mode::Read),
+            device("meter17", Mode::Write),
+        ]);
+        let run = |topic: &'static str| {
+            let context = context.to_owned();
+            async move {
+                let mut record = AuditRecord::default();
+                let response = run_command(topic, "WRITE 12", &context, &mut record).await;
+                let json: serde_json::Value = serde_json::from_str(&response).unwrap();
+                (json["Err"]["code"].to_owned(), record.tag)
+            }
+        };
+
+        let (code, tag) = run("plant/commands/meter17/Power").await;
+        assert_eq!(
+            ("transport", Some("meter17/Power".to_string())),
+            (code.as_str().unwrap(), tag)
+        );
+        let (code, tag) = run("plant/commands/meter1/Power").await;
+        assert_eq!(
+            ("config", Some("meter1/Power".to_string())),
+            (code.as_str().unwrap(), tag)
+        );
+        let (code, _) = run("plant/commands/meter2/Power").await;
+        assert_eq!("invalid_command", code);
+    }
+
+    #[tokio::test]
+    async fn test_cached_read() {
+        use super::execute;
+        use crate::cloud_protocols::audit::AuditRecord;
+        use crate::models::cache;
+        use crate::models::tag::{TagResponse, TagValue};
+
+        let mut dev = unreachable_device("Cached", Command::Holding, Mode::Read);
+        if let DeviceProtocols::ModbusTCP(_, _, connection, _) = &mut dev {
+            connection.cache_max_age_ms = 60000;
+        }
+        let context = context(vec![]);
+        let read = |payload: &'static str| {
+            let (dev, context) = (dev.to_owned(), context.to_owned());
+            async move {
+                let response = execute(&dev, payload, &context, &mut AuditRecord::default()).await;
+                serde_json::from_str::<serde_json::Value>(&response).unwrap()
+            }
+        };
+
+        assert_eq!("transport", read("READ").await["Err"]["code"]);
+        cache::update(
+            &TagResponse {
+                id: "mqtt_test/Cached".to_string(),
+                value: TagValue::I32(42),
+                cached: false,
+                timestamp: None,
+            },
+            Duration::MAX,
+        );
+        let json = read("READ").await;
+        assert_eq!(42, json["Ok"]["value"]["I32"]);
+        assert_eq!(true, json["Ok"]["cached"]);
+        assert!(json["Ok"]["timestamp"].is_string());
+        assert_eq!("transport", read("READ force").await["Err"]["code"]);
+    }
+
+    #[tokio::test]
+    async fn test_write_previous_value() {
+        use super::execute;
+        use crate::cloud_protocols::audit::AuditRecord;
+        use crate::device_protocols::health::{Breaker, DeviceState};
+        use crate::models::cache;
+        use crate::models::tag::{TagResponse, TagValue};
+
+        let breaker = Arc::new(Breaker::new(1, Duration::from_secs(60)));
+        let device = |tag_name: &str, mode: Mode| {
+            let mut dev = unreachable_device(tag_name, Command::Holding, mode);
+            if let DeviceProtocols::ModbusTCP(_, b, _, _) = &mut dev {
+                *b = breaker.clone();
+            }
+            dev
+        };
+        let context = context(vec![]);
+        cache::update(
+            &TagResponse {
+                id: "mqtt_test/Previous".to_string(),
+                value: TagValue::I32(7),
+                cached: false,
+                timestamp: None,
+            },
+            Duration::MAX,
+        );
+
+        // A rejected write does not reach the device.
+        let mut record = AuditRecord::default();
+        execute(
+            &device("Locked", Mode::Read),
+            "WRITE 1",
+            &context,
+            &mut record,
+        )
+        .await;
+        assert_eq!(Some("config"), record.error.as_ref().map(|err| err.code()));
+        assert_eq!(DeviceState::Online, breaker.state());
+
+        let mut record = AuditRecord::default();
+        execute(
+            &device("Previous", Mode::Write),
+            "WRITE 1",
+            &context,
+            &mut record,
+        )
+        .await;
+        // The read failed, so the previous value is the last one polled and the
+        // breaker stops the write.
+        assert_eq!(Some(TagValue::I32(7)), record.previous_value);
+        assert_eq!(Some("offline"), record.error.as_ref().map(|err| err.code()));
+
+        // The previous value is read from the device before writing.
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let fake_device = FakeDevice(Arc::new(Mutex::new(vec![0, 5])));
+        let registers = fake_device.0.clone();
+        tokio::spawn(async move {
+            tokio_modbus::server::tcp::Server::new(address)
+                .serve(move || Ok(fake_device.to_owned()))
+                .await
+        });
+        let tag = modbus::tcp::Tag {
+            mode: Mode::Write,
+            ..modbus::tcp::Tag::for_test("Setpoint")
+        };
+        let dev = DeviceProtocols::ModbusTCP(
+            Arc::new(Bus::new(1, Duration::ZERO)),
+            Arc::new(Breaker::new(3, Duration::from_secs(1))),
+            modbus::tcp::Connection::for_test("mqtt_previous", address),
+            tag,
+        );
+        while tokio::net::TcpStream::connect(address).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut record = AuditRecord::default();
+        execute(&dev, "WRITE 9", &context, &mut record).await;
+        assert!(record.success, "{:?}", record.error);
+        assert_eq!(Some(TagValue::I32(5)), record.previous_value);
+        assert_eq!(vec![0, 9], *registers.lock().unwrap());
+        // The reads served from the cache return the value written.
+        assert_eq!(Some(TagValue::I32(9)), cache::get("mqtt_previous/Setpoint"));
+    }
+
+    // Device that answers the reads with its holding registers 0 and 1 and
+    // stores the ones written.
+    #[derive(Clone)]
+    struct FakeDevice(Arc<Mutex<Vec<u16>>>);
+
+    impl tokio_modbus::server::Service for FakeDevice {
+        type Request = tokio_modbus::prelude::Request;
+        type Response = tokio_modbus::prelude::Response;
+        type Error = std::io::Error;
+        type Future = futures::future::Ready<Result<Self::Response, std::io::Error>>;
+
+        fn call(&self, req: Self::Request) -> Self::Future {
+            use tokio_modbus::prelude::{Request, Response};
+            let mut registers = self.0.lock().unwrap();
+            futures::future::ready(Ok(match req {
+                Request::WriteMultipleRegisters(address, words) => {
+                    let quantity = words.len() as u16;
+                    *registers = words;
+                    Response::WriteMultipleRegisters(address, quantity)
+                }
+                _ => Response::ReadHoldingRegisters(registers.to_owned()),
+            }))
+        }
+    }
 }
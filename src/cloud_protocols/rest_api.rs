@@ -0,0 +1,519 @@
+use super::audit::AuditRecord;
+use super::mqtt::{audit, execute, CommandContext};
+use crate::device_protocols::health::DeviceState;
+use crate::device_protocols::DeviceProtocols;
+use crate::gen_readable_struct;
+use crate::models::cache;
+use crate::models::device::DeviceError;
+use crate::models::stream;
+use crate::models::tag::TagValue;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::Instrument;
+
+pub const REST_API_FILE: &str = "rest_api.ini";
+
+gen_readable_struct!(
+    struct RestApiConfig {
+        ip: std::net::IpAddr,
+        port: u16 = 8080,
+        token: String,
+    }
+);
+
+#[derive(Debug, Serialize)]
+struct DeviceInfo {
+    name: String,
+    state: DeviceState,
+    tags: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct TagInfo {
+    name: String,
+    id: String,
+    mode: String,
+    value: Option<TagValue>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReadQuery {
+    /// Reads the device even if the tag has a fresh cached value.
+    #[serde(default, alias = "live")]
+    force: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamQuery {
+    /// Comma separated `device/tag` globs.
+    filter: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    /// RFC 3339 times, `to` is now by default.
+    from: String,
+    to: Option<String>,
+}
+
+/// Message sent by the stream clients to change their filters.
+#[derive(Debug, Deserialize)]
+struct Subscription {
+    filter: Vec<String>,
+}
+
+/// Routes of the API, all of them but `/health` need the header
+/// `Authorization: Bearer {token}` or, as browsers cannot set it on a
+/// WebSocket, the query parameter `token`.
+pub fn router(token: String, context: Arc<CommandContext>) -> Router {
+    Router::new()
+        .route("/stream", get(stream))
+        .route("/devices", get(devices))
+        .route("/devices/{device}/tags", get(tags))
+        .route("/tags/{device}/{tag}", get(read_tag).put(write_tag))
+        .route("/tags/{device}/{tag}/history", get(tag_history))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::new(token),
+            authenticate,
+        ))
+        .route("/health", get(health))
+        .with_state(context)
+}
+
+pub async fn serve(
+    listener: TcpListener,
+    token: String,
+    context: Arc<CommandContext>,
+) -> std::io::Result<()> {
+    axum::serve(listener, router(token, context)).await
+}
+
+async fn authenticate(State(token): State<Arc<String>>, request: Request, next: Next) -> Response {
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+    let query_token = || {
+        let query = request.uri().query().unwrap_or_default();
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "token")
+            .map(|(_, value)| value.into_owned())
+    };
+    match authorization.or_else(query_token) {
+        Some(received) if same_token(&received, &token) => next.run(request).await,
+        _ => {
+            let err = DeviceError::Unauthorized("Invalid or missing token.".to_string());
+            reply(StatusCode::UNAUTHORIZED, to_json(&Err::<(), _>(err)))
+        }
+    }
+}
+
+// Compares every byte, so the time taken does not tell how much of the token
+// was right.
+fn same_token(received: &str, token: &str) -> bool {
+    received.len() == token.len()
+        && received
+            .bytes()
+            .zip(token.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn health(State(context): State<Arc<CommandContext>>) -> Response {
+    let mut offline: Vec<String> = context
+        .devices
+        .iter()
+        .filter(|dev| dev.state() == DeviceState::Offline)
+        .map(|dev| dev.device_name())
+        .collect();
+    offline.sort();
+    offline.dedup();
+    let status = serde_json::json!({ "status": "ok", "offline": offline });
+    reply(StatusCode::OK, status.to_string())
+}
+
+async fn stream(websocket: WebSocketUpgrade, Query(query): Query<StreamQuery>) -> Response {
+    let filters = query
+        .filter
+        .map(|filter| parse_filters(&filter))
+        .unwrap_or_default();
+    websocket.on_upgrade(move |socket| forward_events(socket, filters))
+}
+
+fn parse_filters(filter: &str) -> Vec<String> {
+    filter
+        .split(',')
+        .map(str::trim)
+        .filter(|glob| !glob.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+// Sends the events of the scheduler that match the filters until the client leaves.
+async fn forward_events(mut socket: WebSocket, mut filters: Vec<String>) {
+    let mut events = stream::subscribe();
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if event.matches(&filters) => {
+                    if socket.send(Message::Text(to_json(&event).into())).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "A stream client is too slow, events skipped");
+                }
+                Err(RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str::<Subscription>(&text) {
+                    Ok(subscription) => filters = subscription.filter,
+                    Err(err) => {
+                        let err = DeviceError::InvalidCommand(format!("Invalid subscription: {}", err));
+                        let msg = to_json(&Err::<(), _>(err));
+                        if socket.send(Message::Text(msg.into())).await.is_err() {
+                            break;
+                        }
+                    }
+                },
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by axum.
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+async fn devices(State(context): State<Arc<CommandContext>>) -> Response {
+    let mut devices: BTreeMap<String, DeviceInfo> = BTreeMap::new();
+    for dev in context.devices.iter() {
+        devices
+            .entry(dev.device_name())
+            .or_insert_with(|| DeviceInfo {
+                name: dev.device_name(),
+                state: dev.state(),
+                tags: 0,
+            })
+            .tags += 1;
+    }
+    reply(
+        StatusCode::OK,
+        to_json(&devices.into_values().collect::<Vec<_>>()),
+    )
+}
+
+async fn tags(State(context): State<Arc<CommandContext>>, Path(device): Path<String>) -> Response {
+    let tags: Vec<TagInfo> = context
+        .devices
+        .iter()
+        .filter(|dev| dev.device_name() == device)
+        .map(|dev| TagInfo {
+            name: dev.tag_name(),
+            id: dev.id(),
+            mode: format!("{:?}", dev.mode()),
+            value: cache::get(&dev.id()),
+        })
+        .collect();
+    match tags.is_empty() {
+        true => not_found(format!("The device {} cannot be found.", device)),
+        false => reply(StatusCode::OK, to_json(&tags)),
+    }
+}
+
+async fn read_tag(
+    State(context): State<Arc<CommandContext>>,
+    Path((device, tag)): Path<(String, String)>,
+    Query(query): Query<ReadQuery>,
+) -> Response {
+    let dev = match find(&context, &device, &tag) {
+        Some(dev) => dev,
+        None => return not_found(format!("The tag {}/{} cannot be found.", device, tag)),
+    };
+    let path = format!("GET /tags/{}/{}", device, tag);
+    let payload = match query.force {
+        true => "READ force",
+        false => "READ",
+    };
+    run(&context, dev, path, payload.to_string()).await
+}
+
+async fn write_tag(
+    State(context): State<Arc<CommandContext>>,
+    Path((device, tag)): Path<(String, String)>,
+    body: String,
+) -> Response {
+    let dev = match find(&context, &device, &tag) {
+        Some(dev) => dev,
+        None => return not_found(format!("The tag {}/{} cannot be found.", device, tag)),
+    };
+    // The body is the value, as in the MQTT WRITE command (i.e. `21.5` or `[1, 0]`).
+    let payload = format!("WRITE {}", body.split_whitespace().collect::<String>());
+    let path = format!("PUT /tags/{}/{}", device, tag);
+    run(&context, dev, path, payload).await
+}
+
+async fn tag_history(
+    State(context): State<Arc<CommandContext>>,
+    Path((device, tag)): Path<(String, String)>,
+    Query(query): Query<HistoryQuery>,
+) -> Response {
+    let dev = match find(&context, &device, &tag) {
+        Some(dev) => dev,
+        None => return not_found(format!("The tag {}/{} cannot be found.", device, tag)),
+    };
+    // An unescaped `+` of the time offset arrives as a space.
+    let time = |time: &str| time.trim().replace(' ', "+");
+    let payload = match query.to {
+        Some(to) => format!("HISTORY {} {}", time(&query.from), time(&to)),
+        None => format!("HISTORY {}", time(&query.from)),
+    };
+    let path = format!("GET /tags/{}/{}/history", device, tag);
+    run(&context, dev, path, payload).await
+}
+
+fn find<'a>(context: &'a CommandContext, device: &str, tag: &str) -> Option<&'a DeviceProtocols> {
+    context
+        .devices
+        .iter()
+        .find(|dev| dev.device_name() == device && dev.tag_name() == tag)
+}
+
+// Runs the command like the MQTT ones, so it is audited and answered with the same JSON.
+async fn run(
+    context: &CommandContext,
+    dev: &DeviceProtocols,
+    path: String,
+    payload: String,
+) -> Response {
+    let span = tracing::info_span!("rest_request", path = %path);
+    async {
+        let started = Instant::now();
+        let mut record = AuditRecord {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            topic: path,
+            ..Default::default()
+        };
+        let response = execute(dev, &payload, context, &mut record).await;
+        record.duration_ms = started.elapsed().as_millis() as u64;
+        tracing::info!(
+            command = %record.command,
+            value = ?record.value,
+            success = record.success,
+            duration_ms = record.duration_ms,
+            "Command processed"
+        );
+        audit(context, &record);
+
+        let status = record.error.as_ref().map_or(StatusCode::OK, status_code);
+        reply(status, response)
+    }
+    .instrument(span)
+    .await
+}
+
+fn status_code(err: &DeviceError) -> StatusCode {
+    match err {
+        DeviceError::Config(_) | DeviceError::InvalidCommand(_) => StatusCode::BAD_REQUEST,
+        DeviceError::Forbidden(_) => StatusCode::FORBIDDEN,
+        DeviceError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+        DeviceError::Offline(_) => StatusCode::SERVICE_UNAVAILABLE,
+        DeviceError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        DeviceError::Transport(_)
+        | DeviceError::Exception(_)
+        | DeviceError::Decode(_)
+        | DeviceError::WriteMismatch(_, _) => StatusCode::BAD_GATEWAY,
+    }
+}
+
+fn not_found(msg: String) -> Response {
+    let err = DeviceError::InvalidCommand(msg);
+    reply(StatusCode::NOT_FOUND, to_json(&Err::<(), _>(err)))
+}
+
+fn reply(status: StatusCode, body: String) -> Response {
+    (status, [(header::CONTENT_TYPE, "application/json")], body).into_response()
+}
+
+// The responses are plain data, so their serialization cannot fail.
+fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cloud_protocols::mqtt::tests::{context, unreachable_device};
+    use crate::device_protocols::modbus::shared::Command;
+    use crate::device_protocols::Mode;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Sends a raw HTTP/1.1 request, returning the status and the body.
+    async fn request(
+        address: std::net::SocketAddr,
+        method: &str,
+        path: &str,
+        token: Option<&str>,
+        body: &str,
+    ) -> (u16, serde_json::Value) {
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let authorization = token
+            .map(|token| format!("Authorization: Bearer {}\r\n", token))
+            .unwrap_or_default();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: gateway\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            authorization,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let status = response[9..12].parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_rest_api() {
+        let devices = vec![
+            unreachable_device("Setpoint", Command::Holding, Mode::Write),
+            unreachable_device("Energy", Command::Holding, Mode::Read),
+        ];
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = super::router("secret".to_string(), context(devices));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (status, json) = request(address, "GET", "/health", None, "").await;
+        assert_eq!((200, "ok"), (status, json["status"].as_str().unwrap()));
+
+        let (status, json) = request(address, "GET", "/devices", None, "").await;
+        assert_eq!(
+            (401, "unauthorized"),
+            (status, json["Err"]["code"].as_str().unwrap())
+        );
+        let (status, _) = request(address, "GET", "/devices", Some("wrong"), "").await;
+        assert_eq!(401, status);
+        let (status, _) = request(address, "GET", "/devices", Some("secreT"), "").await;
+        assert_eq!(401, status);
+
+        let (status, json) = request(address, "GET", "/devices", Some("secret"), "").await;
+        assert_eq!(200, status);
+        assert_eq!("mqtt_test", json[0]["name"]);
+        assert_eq!(2, json[0]["tags"]);
+
+        let path = "/devices/mqtt_test/tags";
+        let (status, json) = request(address, "GET", path, Some("secret"), "").await;
+        assert_eq!(200, status);
+        assert_eq!("mqtt_test/Setpoint", json[0]["id"]);
+        assert_eq!("Write", json[0]["mode"]);
+        let path = "/devices/unknown/tags";
+        let (status, _) = request(address, "GET", path, Some("secret"), "").await;
+        assert_eq!(404, status);
+
+        let path = "/tags/mqtt_test/Setpoint";
+        let (status, json) = request(address, "GET", path, Some("secret"), "").await;
+        assert_eq!(
+            (502, "transport"),
+            (status, json["Err"]["code"].as_str().unwrap())
+        );
+        let (status, json) = request(address, "PUT", path, Some("secret"), "12").await;
+        assert_eq!(
+            (502, "transport"),
+            (status, json["Err"]["code"].as_str().unwrap())
+        );
+        let (status, json) = request(address, "PUT", path, Some("secret"), "abc").await;
+        assert_eq!(
+            (400, "invalid_command"),
+            (status, json["Err"]["code"].as_str().unwrap())
+        );
+
+        let path = "/tags/mqtt_test/Energy";
+        let (status, json) = request(address, "PUT", path, Some("secret"), "1").await;
+        assert_eq!(
+            (400, "config"),
+            (status, json["Err"]["code"].as_str().unwrap())
+        );
+        let path = "/tags/mqtt_test/Setpoint/history?from=2024-01-01T00:00:00+01:00";
+        let (status, json) = request(address, "GET", path, Some("secret"), "").await;
+        assert_eq!(
+            (400, "config"),
+            (status, json["Err"]["code"].as_str().unwrap())
+        );
+        let path = "/tags/mqtt_test/Setpoint/history?from=yesterday";
+        let (status, json) = request(address, "GET", path, Some("secret"), "").await;
+        assert_eq!(
+            (400, "invalid_command"),
+            (status, json["Err"]["code"].as_str().unwrap())
+        );
+
+        let path = "/tags/mqtt_test/Unknown";
+        let (status, _) = request(address, "GET", path, Some("secret"), "").await;
+        assert_eq!(404, status);
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        use crate::models::stream::{publish, StreamEvent};
+        use crate::models::tag::TagValue;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = super::router("secret".to_string(), context(vec![]));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (status, _) = request(address, "GET", "/stream", None, "").await;
+        assert_eq!(401, status);
+
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let handshake = "GET /stream?token=secret&filter=stream_dev/*%2Cother/Temp HTTP/1.1\r\n\
+            Host: gateway\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+            Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        stream.write_all(handshake.as_bytes()).await.unwrap();
+        let mut buffer = vec![0; 1024];
+        let read = stream.read(&mut buffer).await.unwrap();
+        assert!(String::from_utf8_lossy(&buffer[..read]).starts_with("HTTP/1.1 101"));
+
+        let event = |device: &str| StreamEvent::Measure {
+            device: device.to_string(),
+            tag: "Temp".to_string(),
+            timestamp: String::new(),
+            value: Ok(TagValue::I32(7)),
+        };
+        // The subscription starts after the upgrade, so the events are sent until one arrives.
+        let frame = loop {
+            publish(event("filtered_dev"));
+            publish(event("stream_dev"));
+            let read = tokio::time::timeout(
+                std::time::Duration::from_millis(50),
+                stream.read(&mut buffer),
+            )
+            .await;
+            if let Ok(read) = read {
+                break buffer[..read.unwrap()].to_vec();
+            }
+        };
+        // Unmasked text frame shorter than 126 bytes.
+        assert_eq!(0x81, frame[0]);
+        let json: serde_json::Value =
+            serde_json::from_slice(&frame[2..2 + frame[1] as usize]).unwrap();
+        assert_eq!("stream_dev", json["device"]);
+        assert_eq!(7, json["value"]["Ok"]["I32"]);
+    }
+}
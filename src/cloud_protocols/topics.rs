@@ -0,0 +1,348 @@
+use super::mqtt::MqttIniConfig;
+use crate::gen_matcher;
+use crate::models::device::DeviceError;
+use crate::models::tag::TagResponse;
+use serde_json::Value;
+use std::str::FromStr;
+
+gen_matcher!(
+    enum TopicLayout {
+        Device,
+        Tag,
+        Template,
+    }
+);
+
+gen_matcher!(
+    enum PayloadShape {
+        Json,
+        Raw,
+    }
+);
+
+/// Custom placeholders of the topic template, as `name=value` pairs separated
+/// by commas (i.e. `site=madrid, line=3`).
+#[derive(Debug, Clone, Default)]
+pub struct Placeholders(pub Vec<(String, String)>);
+
+impl FromStr for Placeholders {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.split_once('=') {
+                Some((name, value)) => Ok((name.trim().to_string(), value.trim().to_string())),
+                None => Err(()),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Placeholders)
+    }
+}
+
+/// Message ready to be published.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Publication {
+    pub topic: String,
+    pub payload: String,
+    pub retain: bool,
+}
+
+// The publications are plain data, so their serialization cannot fail.
+fn to_json<T: serde::Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+/// Topics and payloads of the values published without request, as configured
+/// in mqtt.ini.
+#[derive(Debug, Clone)]
+pub struct Topics {
+    prefix: String,
+    layout: TopicLayout,
+    template: String,
+    placeholders: Placeholders,
+    payload: PayloadShape,
+    retain_measures: bool,
+    retain_aggregates: bool,
+    retain_status: bool,
+}
+
+impl Topics {
+    pub fn new(config: &MqttIniConfig) -> Result<Self, String> {
+        let topics = Topics {
+            prefix: config.mqtt_topic_installation_prefix.to_owned(),
+            layout: config.topic_layout.to_owned(),
+            template: config.topic_template.to_owned(),
+            placeholders: config.topic_placeholders.to_owned(),
+            payload: config.payload.to_owned(),
+            retain_measures: config.retain_measures,
+            retain_aggregates: config.retain_aggregates,
+            retain_status: config.retain_status,
+        };
+        // The topics are published, so they cannot subscribe with wildcards.
+        let topic = topics.topic("class", "device", "tag");
+        if topic.contains(['+', '#']) {
+            return Err(format!(
+                "The topic {} cannot have the MQTT wildcards + or #.",
+                topic
+            ));
+        }
+        if topics.layout == TopicLayout::Template {
+            if let Some(start) = topic.find('{') {
+                let end = topic[start..]
+                    .find('}')
+                    .map_or(topic.len(), |end| start + end + 1);
+                return Err(format!(
+                    "Unknown placeholder {} in the topic template.",
+                    &topic[start..end]
+                ));
+            }
+        }
+        Ok(topics)
+    }
+
+    // Topic of a class of values (measures, aggregates or status) of a tag, the
+    // status has no tag and the empty levels of every layout are removed. The
+    // Device layout publishes all the tags in one topic, without class for the
+    // measures.
+    fn topic(&self, class: &str, device: &str, tag: &str) -> String {
+        let topic = match (&self.layout, class) {
+            (TopicLayout::Device, "measures") => format!("{}/{}", self.prefix, device),
+            (TopicLayout::Device, _) => format!("{}/{}/{}", self.prefix, device, class),
+            (TopicLayout::Tag, _) => format!("{}/{}/{}/{}", self.prefix, class, device, tag),
+            (TopicLayout::Template, _) => {
+                let mut topic = self
+                    .template
+                    .replace("{prefix}", &self.prefix)
+                    .replace("{class}", class)
+                    .replace("{device}", device)
+                    .replace("{tag}", tag);
+                for (name, value) in self.placeholders.0.iter() {
+                    topic = topic.replace(&format!("{{{}}}", name), value);
+                }
+                topic
+            }
+        };
+        let levels: Vec<&str> = topic.split('/').filter(|l| !l.is_empty()).collect();
+        levels.join("/")
+    }
+
+    /// Publications of the values read of a device, each one with the name of its tag.
+    pub fn measures(
+        &self,
+        device: &str,
+        values: &[(String, Result<TagResponse, DeviceError>)],
+    ) -> Vec<Publication> {
+        if values.is_empty() {
+            return Vec::new();
+        }
+        let retain = self.retain_measures;
+        if self.layout == TopicLayout::Device {
+            let values: Vec<&Result<TagResponse, DeviceError>> =
+                values.iter().map(|(_, value)| value).collect();
+            return vec![Publication {
+                topic: self.topic("measures", device, ""),
+                payload: to_json(&values),
+                retain,
+            }];
+        }
+        values
+            .iter()
+            .map(|(tag, value)| Publication {
+                topic: self.topic("measures", device, tag),
+                payload: match (&self.payload, value) {
+                    (PayloadShape::Raw, Ok(response)) => response.value.to_string(),
+                    (PayloadShape::Raw, Err(err)) => to_json(err),
+                    (PayloadShape::Json, value) => to_json(value),
+                },
+                retain,
+            })
+            .collect()
+    }
+
+    /// Publications of the aggregates of the windows ended of a device.
+    pub fn aggregates(&self, device: &str, aggregates: &[Value]) -> Vec<Publication> {
+        if aggregates.is_empty() {
+            return Vec::new();
+        }
+        let retain = self.retain_aggregates;
+        if self.layout == TopicLayout::Device {
+            return vec![Publication {
+                topic: self.topic("aggregates", device, ""),
+                payload: to_json(&aggregates),
+                retain,
+            }];
+        }
+        aggregates
+            .iter()
+            .map(|aggregate| {
+                let id = aggregate["id"].as_str().unwrap_or_default();
+                let tag = id.strip_prefix(&format!("{}/", device)).unwrap_or(id);
+                Publication {
+                    topic: self.topic("aggregates", device, tag),
+                    payload: to_json(aggregate),
+                    retain,
+                }
+            })
+            .collect()
+    }
+
+    /// Publication of a change of state of a device.
+    pub fn status(&self, device: &str, status: &Value) -> Publication {
+        Publication {
+            topic: self.topic("status", device, ""),
+            payload: status.to_string(),
+            retain: self.retain_status,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Placeholders, Topics};
+    use crate::cloud_protocols::mqtt::MqttIniConfig;
+    use crate::models::device::DeviceError;
+    use crate::models::tag::{TagResponse, TagValue};
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    // Topics of a mqtt.ini with the given topic settings.
+    fn configure(settings: &[(&str, &str)]) -> Result<Topics, String> {
+        let mut section: HashMap<String, String> = [
+            ("protocol", "TCP"),
+            ("host", "localhost"),
+            ("port", "1883"),
+            ("qos", "AtLeastOnce"),
+            ("mqtt_topic_installation_prefix", "client/site"),
+            ("topic_placeholders", "area=north, line = 3"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        section.extend(settings.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+        Topics::new(&MqttIniConfig::try_from(section)?)
+    }
+
+    fn values() -> Vec<(String, Result<TagResponse, DeviceError>)> {
+        let response = TagResponse {
+            id: "meter/Power".to_string(),
+            value: TagValue::F32(12.5),
+            cached: false,
+            timestamp: None,
+        };
+        vec![
+            ("Power".to_string(), Ok(response)),
+            (
+                "Energy".to_string(),
+                Err(DeviceError::Timeout(Duration::from_secs(1))),
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_device_layout() {
+        let topics = configure(&[("payload", "Raw"), ("retain_status", "true")]).unwrap();
+        let measures = topics.measures("meter", &values());
+        assert_eq!(1, measures.len());
+        assert_eq!("client/site/meter", measures[0].topic);
+        assert_eq!(
+            r#"[{"Ok":{"id":"meter/Power","value":{"F32":12.5}}},{"Err":{"code":"timeout","message":"Timeout after 1000 ms."}}]"#,
+            measures[0].payload
+        );
+        assert!(!measures[0].retain);
+        let aggregates = topics.aggregates("meter", &[serde_json::json!({"id": "meter/Power"})]);
+        assert_eq!("client/site/meter/aggregates", aggregates[0].topic);
+        let status = topics.status("meter", &serde_json::json!({"state": "Online"}));
+        assert_eq!("client/site/meter/status", status.topic);
+        assert!(status.retain);
+        assert!(topics.measures("meter", &[]).is_empty());
+
+        // The empty levels are removed as in the other layouts.
+        let topics = configure(&[("mqtt_topic_installation_prefix", "/client//site/")]).unwrap();
+        assert_eq!(
+            "client/site/meter",
+            topics.measures("meter", &values())[0].topic
+        );
+        let status = topics.status("meter", &serde_json::json!({}));
+        assert_eq!("client/site/meter/status", status.topic);
+    }
+
+    #[test]
+    fn test_tag_layout() {
+        let topics = configure(&[
+            ("topic_layout", "Tag"),
+            ("payload", "Raw"),
+            ("retain_measures", "true"),
+        ])
+        .unwrap();
+        let measures = topics.measures("meter", &values());
+        assert_eq!("client/site/measures/meter/Power", measures[0].topic);
+        assert_eq!("12.5", measures[0].payload);
+        assert!(measures[0].retain);
+        assert_eq!("client/site/measures/meter/Energy", measures[1].topic);
+        assert_eq!(
+            r#"{"code":"timeout","message":"Timeout after 1000 ms."}"#,
+            measures[1].payload
+        );
+        let aggregates = topics.aggregates(
+            "meter",
+            &[serde_json::json!({"id": "meter/Power", "max": 1.0})],
+        );
+        assert_eq!("client/site/aggregates/meter/Power", aggregates[0].topic);
+        assert_eq!(r#"{"id":"meter/Power","max":1.0}"#, aggregates[0].payload);
+        let status = topics.status("meter", &serde_json::json!({}));
+        assert_eq!("client/site/status/meter", status.topic);
+    }
+
+    #[test]
+    fn test_template_layout() {
+        let template = "{prefix}/{area}/{line}/{device}/{class}/{tag}";
+        let topics =
+            configure(&[("topic_layout", "Template"), ("topic_template", template)]).unwrap();
+        let measures = topics.measures("meter", &values());
+        assert_eq!(
+            "client/site/north/3/meter/measures/Power",
+            measures[0].topic
+        );
+        assert_eq!(
+            r#"{"Ok":{"id":"meter/Power","value":{"F32":12.5}}}"#,
+            measures[0].payload
+        );
+        let status = topics.status("meter", &serde_json::json!({}));
+        assert_eq!("client/site/north/3/meter/status", status.topic);
+
+        let unknown = [
+            ("topic_layout", "Template"),
+            ("topic_template", "{prefix}/{site}/{tag}"),
+        ];
+        assert_eq!(
+            Err("Unknown placeholder {site} in the topic template.".to_string()),
+            configure(&unknown).map(|_| ())
+        );
+        assert!("site".parse::<Placeholders>().is_err());
+    }
+
+    #[test]
+    fn test_wildcards() {
+        let wildcard = |settings: &[(&str, &str)]| {
+            configure(settings)
+                .unwrap_err()
+                .contains("cannot have the MQTT wildcards")
+        };
+        assert!(wildcard(&[("mqtt_topic_installation_prefix", "client/+")]));
+        assert!(wildcard(&[
+            ("topic_layout", "Tag"),
+            ("mqtt_topic_installation_prefix", "client/#")
+        ]));
+        assert!(wildcard(&[
+            ("topic_layout", "Template"),
+            ("topic_template", "{prefix}/+/{device}/{tag}")
+        ]));
+        assert!(wildcard(&[
+            ("topic_layout", "Template"),
+            ("topic_template", "{prefix}/{area}/{tag}"),
+            ("topic_placeholders", "area=#")
+        ]));
+    }
+}
@@ -2,6 +2,8 @@ use ini::Ini;
 use std::collections::HashMap;
 use std::fmt::Debug;
 
+pub const PROFILES_FOLDER: &str = "profiles";
+
 fn parse_section(fhandler: &Ini, section: Option<&str>) -> Result<HashMap<String, String>, String> {
     let section_name = section.expect("Error while parsing a section name.");
     let section_params = fhandler
@@ -12,37 +14,145 @@ fn parse_section(fhandler: &Ini, section: Option<&str>) -> Result<HashMap<String
     // Insert the section name as the name parameter in the hashmap
     section_data.insert("name".to_string(), section_name.to_string());
 
-    // Insert each parameter and value in the hashmap
-    section_params.iter().for_each(|(param_name, param_value)| {
-        section_data.insert(param_name.to_string(), param_value.to_string());
-    });
+    // Insert each parameter and its interpolated value in the hashmap
+    for (param_name, param_value) in section_params.iter() {
+        let value = interpolate(param_value).map_err(|err| {
+            format!(
+                "Error in the field {} of section {}: {}",
+                param_name, section_name, err
+            )
+        })?;
+        section_data.insert(param_name.to_string(), value);
+    }
     Ok(section_data)
 }
 
+/// Replaces every `${ENV_VAR}`, `${ENV_VAR:-default}` and `${file:/path/to/secret}`
+/// reference of the value. As in the shell, the default is used when the variable
+/// is unset or empty, while `${ENV_VAR}` only fails when it is unset.
+fn interpolate(value: &str) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..]
+            .find('}')
+            .ok_or(format!("Unclosed reference in {}.", value))?;
+        let reference = &rest[start + 2..start + end];
+
+        let replacement = match reference.strip_prefix("file:") {
+            Some(path) => std::fs::read_to_string(path)
+                .map(|secret| secret.trim_end_matches(['\r', '\n']).to_string())
+                .map_err(|err| format!("The file {} cannot be read: {}.", path, err))?,
+            None => {
+                let (variable, default) = match reference.split_once(":-") {
+                    Some((variable, default)) => (variable, Some(default)),
+                    None => (reference, None),
+                };
+                match (std::env::var(variable), default) {
+                    (Ok(env_value), Some(default)) if env_value.is_empty() => default.to_string(),
+                    (Ok(env_value), _) => env_value,
+                    (Err(_), Some(default)) => default.to_string(),
+                    (Err(_), None) => return Err(format!("The variable {} is not set.", variable)),
+                }
+            }
+        };
+
+        result.push_str(&rest[..start]);
+        result.push_str(&replacement);
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn read_sections(filename: &str) -> Vec<HashMap<String, String>> {
+    let fhandler = ini::Ini::load_from_file(filename)
+        .unwrap_or_else(|_| panic!("Error opening or parsing file {}.", filename));
+
+    fhandler
+        .sections()
+        .map(|section| {
+            parse_section(&fhandler, section)
+                .unwrap_or_else(|err| panic!("Error while parsing file {}. {}", filename, err))
+        })
+        .collect()
+}
+
+fn build<T>(sections: Vec<HashMap<String, String>>, filename: &str) -> Vec<T>
+where
+    T: TryFrom<HashMap<String, String>>,
+    <T as TryFrom<HashMap<String, String>>>::Error: Debug,
+{
+    sections
+        .into_iter()
+        .map(|data| {
+            T::try_from(data)
+                .unwrap_or_else(|err| panic!("Error while parsing file {}. {:?}", filename, err))
+        })
+        .collect()
+}
+
 pub fn read_file<T>(filename: &str) -> Vec<T>
 where
     T: TryFrom<HashMap<String, String>>,
     <T as TryFrom<HashMap<String, String>>>::Error: Debug,
 {
-    let mut vec = Vec::new();
-    let fhandler = ini::Ini::load_from_file(filename)
-        .unwrap_or_else(|_| panic!("Error opening or parsing file {}.", filename));
+    build(remove_disabled(read_sections(filename)), filename)
+}
 
-    // Iterate in every section to get the needed
-    fhandler.sections().for_each(|section| {
-        let data = parse_section(&fhandler, section)
-            .unwrap_or_else(|_| panic!("Error while parsing file {}.", filename));
+/// Reads the tags of a device. When `profile` is not empty the tags are taken from
+/// `profiles/{profile}.ini` and the sections of `filename` (if it exists) are applied
+/// over them as per device overrides.
+pub fn read_file_with_profile<T>(profile: &str, filename: &str) -> Vec<T>
+where
+    T: TryFrom<HashMap<String, String>>,
+    <T as TryFrom<HashMap<String, String>>>::Error: Debug,
+{
+    if profile.is_empty() {
+        return read_file(filename);
+    }
 
-        let parsed_data =
-            T::try_from(data).unwrap_or_else(|_| panic!("Error while parsing file {}.", filename));
-        vec.push(parsed_data);
-    });
-    vec
+    let profile_file = format!("{}/{}.ini", PROFILES_FOLDER, profile);
+    let overrides = match std::path::Path::new(filename).exists() {
+        true => read_sections(filename),
+        false => Vec::new(),
+    };
+    let sections = apply_overrides(read_sections(&profile_file), overrides);
+    build(remove_disabled(sections), filename)
+}
+
+/// Merges every override section into the base section with the same name,
+/// the sections that do not exist in the base are appended as new ones.
+fn apply_overrides(
+    base: Vec<HashMap<String, String>>,
+    overrides: Vec<HashMap<String, String>>,
+) -> Vec<HashMap<String, String>> {
+    let mut sections = base;
+    for section in overrides {
+        match sections
+            .iter_mut()
+            .find(|s| s.get("name") == section.get("name"))
+        {
+            Some(base_section) => base_section.extend(section),
+            None => sections.push(section),
+        }
+    }
+    sections
+}
+
+/// Drops the sections marked with `enabled=false`.
+fn remove_disabled(sections: Vec<HashMap<String, String>>) -> Vec<HashMap<String, String>> {
+    sections
+        .into_iter()
+        .filter(|s| s.get("enabled").map(|e| e.as_str()) != Some("false"))
+        .collect()
 }
 
 #[macro_export]
 macro_rules! gen_matcher {
-    (enum $e_name:ident { $( $field:ident ),*, }) => {
+    ($(#[$meta:meta])* enum $e_name:ident { $( $field:ident ),*, }) => {
+        $(#[$meta])*
         #[derive(Debug, Clone, PartialEq)]
         pub enum $e_name {
             $(
@@ -65,9 +175,17 @@ macro_rules! gen_matcher {
     };
 }
 
+/// Generates a struct readable from an ini section. A field declared as
+/// `field: Type = default` is optional and takes the default value when missing.
 #[macro_export]
 macro_rules! gen_readable_struct {
-    (struct $s_name:ident { $( $field:ident:$type:ty),*, }) => {
+    (@missing ($error:expr)) => {
+        return Err($error)
+    };
+    (@missing ($error:expr) $default:expr) => {
+        $default
+    };
+    (struct $s_name:ident { $( $field:ident:$type:ty $(= $default:expr)? ),*, }) => {
 
         #[derive(Debug, Clone)]
         pub struct $s_name {
@@ -77,12 +195,14 @@ macro_rules! gen_readable_struct {
         impl TryFrom<std::collections::HashMap<String, String>> for $s_name {
             type Error = String;
             fn try_from(value: std::collections::HashMap<String, String>) -> Result<Self, Self::Error> {
-                let field_error = |field| format!("The field {} cannot be found.", field);
+                // Unused when every field has a default value.
+                #[allow(unused_variables)]
+                let field_error = |field: &str| format!("The field {} cannot be found.", field);
                 let parse_error = |field, value| format!("The value {} of the field {} cannot be parsed.", value, field );
 
                 $(
-                    let $field = match value.get(stringify!($field)) {
-                        None        => return Err(field_error(stringify!($field))),
+                    let $field: $type = match value.get(stringify!($field)) {
+                        None        => $crate::gen_readable_struct!(@missing (field_error(stringify!($field))) $($default)?),
                         Some(value) => match value.parse() {
                             Err(_)      => return Err(parse_error(stringify!($field), value)),
                             Ok(parsed)  => parsed,
@@ -101,3 +221,73 @@ macro_rules! gen_readable_struct {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    fn section(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        use super::{apply_overrides, remove_disabled};
+
+        let profile = vec![
+            section(&[("name", "Tension_R"), ("address", "7"), ("multiplier", "1")]),
+            section(&[("name", "Tension_S"), ("address", "9"), ("multiplier", "1")]),
+        ];
+        let overrides = vec![
+            section(&[("name", "Tension_R"), ("multiplier", "0.1")]),
+            section(&[("name", "Tension_S"), ("enabled", "false")]),
+            section(&[("name", "Extra"), ("address", "40")]),
+        ];
+
+        let merged = remove_disabled(apply_overrides(profile, overrides));
+        assert_eq!(2, merged.len());
+        assert_eq!(Some(&"0.1".to_string()), merged[0].get("multiplier"));
+        assert_eq!(Some(&"7".to_string()), merged[0].get("address"));
+        assert_eq!(Some(&"Extra".to_string()), merged[1].get("name"));
+    }
+
+    #[test]
+    fn test_interpolate() {
+        use super::interpolate;
+
+        std::env::set_var("IOT_GATEWAY_TEST_HOST", "broker.local");
+        std::env::set_var("IOT_GATEWAY_TEST_EMPTY", "");
+        let secret_file = std::env::temp_dir().join("iot_gateway_test_secret");
+        std::fs::write(&secret_file, "s3cr3t\n").unwrap();
+
+        assert_eq!(
+            Ok("tcp://broker.local:1883".to_string()),
+            interpolate("tcp://${IOT_GATEWAY_TEST_HOST}:1883")
+        );
+        assert_eq!(
+            Ok("1883".to_string()),
+            interpolate("${IOT_GATEWAY_TEST_UNSET_PORT:-1883}")
+        );
+        assert_eq!(
+            Ok("s3cr3t".to_string()),
+            interpolate(&format!("${{file:{}}}", secret_file.display()))
+        );
+        assert_eq!(
+            Err("The variable IOT_GATEWAY_TEST_UNSET_PORT is not set.".to_string()),
+            interpolate("${IOT_GATEWAY_TEST_UNSET_PORT}")
+        );
+        // An empty variable takes the default but is accepted without it.
+        assert_eq!(
+            Ok("1883".to_string()),
+            interpolate("${IOT_GATEWAY_TEST_EMPTY:-1883}")
+        );
+        assert_eq!(
+            Ok("user:@host".to_string()),
+            interpolate("user:${IOT_GATEWAY_TEST_EMPTY}@host")
+        );
+        assert_eq!(Ok("plain".to_string()), interpolate("plain"));
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Lane used by a request while it waits for the bus. The on demand commands
+/// are served before the scheduled polls, but a waiting poll is served after
+/// `MAX_COMMANDS_AHEAD` commands, so a flood of commands cannot starve it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Priority {
+    Command,
+    Poll,
+}
+
+const MAX_COMMANDS_AHEAD: usize = 4;
+
+#[derive(Debug)]
+struct Lanes {
+    available: usize,
+    commands: VecDeque<oneshot::Sender<()>>,
+    polls: VecDeque<oneshot::Sender<()>>,
+    // Commands served in a row while a poll was waiting.
+    commands_ahead: usize,
+}
+
+/// Physical bus or TCP endpoint shared by several devices. It limits the
+/// requests running at the same time and keeps a delay after each one, the
+/// waiting requests are served in arrival order inside each priority lane.
+#[derive(Debug)]
+pub struct Bus {
+    lanes: Mutex<Lanes>,
+    max_concurrent_requests: usize,
+    delay: Duration,
+}
+
+// Buses indexed by endpoint, so every device reached through the same
+// endpoint shares it even if they are configured in different folders.
+static BUSES: LazyLock<Mutex<HashMap<String, Arc<Bus>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Permission to use the bus, it is handed to the next waiting request on drop.
+struct Permit<'a>(&'a Bus);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+// A request waiting for its permit. If it is cancelled (i.e. by a timeout)
+// after the permit was handed to it, the permit goes back to the bus.
+struct Waiting<'a> {
+    bus: &'a Bus,
+    receiver: oneshot::Receiver<()>,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.receiver.close();
+        if self.receiver.try_recv().is_ok() {
+            self.bus.release();
+        }
+    }
+}
+
+impl Bus {
+    pub fn new(max_concurrent_requests: usize, delay: Duration) -> Self {
+        Bus {
+            lanes: Mutex::new(Lanes {
+                available: max_concurrent_requests.max(1),
+                commands: VecDeque::new(),
+                polls: VecDeque::new(),
+                commands_ahead: 0,
+            }),
+            max_concurrent_requests,
+            delay,
+        }
+    }
+
+    /// Returns the bus of the endpoint, creating it with the given limits if it
+    /// is the first device that uses it. The limits of the next devices are
+    /// ignored, so a warning is logged when they are different.
+    pub fn for_endpoint(
+        endpoint: &str,
+        max_concurrent_requests: usize,
+        delay: Duration,
+    ) -> Arc<Bus> {
+        let bus = BUSES
+            .lock()
+            .unwrap()
+            .entry(endpoint.to_string())
+            .or_insert_with(|| Arc::new(Bus::new(max_concurrent_requests, delay)))
+            .to_owned();
+        if bus.max_concurrent_requests != max_concurrent_requests || bus.delay != delay {
+            tracing::warn!(
+                endpoint,
+                max_concurrent_requests = bus.max_concurrent_requests,
+                inter_request_delay_ms = bus.delay.as_millis() as u64,
+                "The devices of the endpoint have different bus limits, the first ones are used"
+            );
+        }
+        bus
+    }
+
+    async fn acquire(&self, priority: Priority) -> Permit<'_> {
+        let receiver = {
+            let mut lanes = self.lanes.lock().unwrap();
+            if lanes.available > 0 {
+                lanes.available -= 1;
+                return Permit(self);
+            }
+            let (sender, receiver) = oneshot::channel();
+            match priority {
+                Priority::Command => lanes.commands.push_back(sender),
+                Priority::Poll => lanes.polls.push_back(sender),
+            }
+            receiver
+        };
+
+        let mut waiting = Waiting {
+            bus: self,
+            receiver,
+        };
+        // The sender is only dropped after handing the permit.
+        let _ = (&mut waiting.receiver).await;
+        Permit(self)
+    }
+
+    fn release(&self) {
+        let mut lanes = self.lanes.lock().unwrap();
+        loop {
+            let poll_turn = lanes.commands.is_empty()
+                || (!lanes.polls.is_empty() && lanes.commands_ahead >= MAX_COMMANDS_AHEAD);
+            let next = match poll_turn {
+                true => lanes.polls.pop_front(),
+                false => lanes.commands.pop_front(),
+            };
+            match next {
+                // The request could have been cancelled while waiting.
+                Some(sender) => {
+                    if sender.send(()).is_ok() {
+                        lanes.commands_ahead = match !poll_turn && !lanes.polls.is_empty() {
+                            true => lanes.commands_ahead + 1,
+                            false => 0,
+                        };
+                        return;
+                    }
+                }
+                None => {
+                    lanes.available += 1;
+                    return;
+                }
+            }
+        }
+    }
+
+    pub async fn run<F: Future>(&self, priority: Priority, request: F) -> F::Output {
+        let _permit = self.acquire(priority).await;
+        let result = request.await;
+
+        // Making this little sleep we block the bus during X time
+        // this time gives the cheaper devices some more time to handle
+        // the next request.
+        tokio::time::sleep(self.delay).await;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bus, Priority};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_bus_limits_concurrent_requests() {
+        let bus = Arc::new(Bus::new(2, Duration::ZERO));
+        let (running, max_running) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+
+        let requests = (0..6).map(|_| {
+            let (bus, running, max_running) = (bus.clone(), running.clone(), max_running.clone());
+            tokio::spawn(async move {
+                bus.run(Priority::Poll, async {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+                .await
+            })
+        });
+        futures::future::join_all(requests).await;
+
+        assert_eq!(2, max_running.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_bus_serves_commands_first() {
+        let bus = Arc::new(Bus::new(1, Duration::ZERO));
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let busy = {
+            let bus = bus.clone();
+            tokio::spawn(async move {
+                bus.run(
+                    Priority::Poll,
+                    tokio::time::sleep(Duration::from_millis(50)),
+                )
+                .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let request = |name: &'static str, priority: Priority| {
+            let (bus, order) = (bus.clone(), order.clone());
+            tokio::spawn(async move {
+                bus.run(priority, async { order.lock().unwrap().push(name) })
+                    .await
+            })
+        };
+        let poll = request("poll", Priority::Poll);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        // A cancelled command must not keep the bus busy.
+        let cancelled = tokio::time::timeout(
+            Duration::from_millis(1),
+            bus.run(Priority::Command, async {}),
+        );
+        assert!(cancelled.await.is_err());
+
+        let command = request("command", Priority::Command);
+        futures::future::join_all([busy, poll, command]).await;
+
+        assert_eq!(vec!["command", "poll"], *order.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_bus_does_not_starve_polls() {
+        use super::MAX_COMMANDS_AHEAD;
+
+        let bus = Arc::new(Bus::new(1, Duration::ZERO));
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let busy = {
+            let bus = bus.clone();
+            tokio::spawn(async move {
+                bus.run(
+                    Priority::Poll,
+                    tokio::time::sleep(Duration::from_millis(50)),
+                )
+                .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let mut requests = vec![busy];
+        let priorities = std::iter::once(Priority::Poll).chain(std::iter::repeat_n(
+            Priority::Command,
+            MAX_COMMANDS_AHEAD + 2,
+        ));
+        for priority in priorities {
+            let (bus, order) = (bus.clone(), order.clone());
+            requests.push(tokio::spawn(async move {
+                bus.run(priority, async { order.lock().unwrap().push(priority) })
+                    .await
+            }));
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        futures::future::join_all(requests).await;
+
+        let mut expected = vec![Priority::Command; MAX_COMMANDS_AHEAD + 2];
+        expected.insert(MAX_COMMANDS_AHEAD, Priority::Poll);
+        assert_eq!(expected, *order.lock().unwrap());
+    }
+}
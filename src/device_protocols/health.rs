@@ -0,0 +1,145 @@
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How a device request is timed out and retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub timeout: Duration,
+    pub retries: u32,
+    pub backoff: Duration,
+    pub backoff_max: Duration,
+}
+
+impl RetryPolicy {
+    /// Time to wait before the retry number `attempt` (starting at 1), it is
+    /// doubled on every retry up to `backoff_max`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.backoff.saturating_mul(factor).min(self.backoff_max)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum DeviceState {
+    Online,
+    Offline,
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    state: DeviceState,
+    failures: u32,
+    last_probe: Instant,
+    changed: bool,
+}
+
+/// Circuit breaker of a device. After `max_failures` consecutive failed
+/// requests the device is marked offline and only one request every
+/// `probe_interval` reaches it until it answers again.
+#[derive(Debug)]
+pub struct Breaker {
+    max_failures: u32,
+    probe_interval: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl Breaker {
+    pub fn new(max_failures: u32, probe_interval: Duration) -> Self {
+        Breaker {
+            max_failures: max_failures.max(1),
+            probe_interval,
+            state: Mutex::new(BreakerState {
+                state: DeviceState::Online,
+                failures: 0,
+                last_probe: Instant::now(),
+                changed: false,
+            }),
+        }
+    }
+
+    /// Returns if a request can be sent to the device.
+    pub fn allow(&self) -> bool {
+        let mut breaker = self.state.lock().unwrap();
+        if breaker.state == DeviceState::Online {
+            return true;
+        }
+        if breaker.last_probe.elapsed() >= self.probe_interval {
+            breaker.last_probe = Instant::now();
+            return true;
+        }
+        false
+    }
+
+    pub fn success(&self) {
+        let mut breaker = self.state.lock().unwrap();
+        breaker.failures = 0;
+        if breaker.state == DeviceState::Offline {
+            breaker.state = DeviceState::Online;
+            breaker.changed = true;
+        }
+    }
+
+    pub fn failure(&self) {
+        let mut breaker = self.state.lock().unwrap();
+        breaker.failures = breaker.failures.saturating_add(1);
+        if breaker.state == DeviceState::Online && breaker.failures >= self.max_failures {
+            breaker.state = DeviceState::Offline;
+            breaker.last_probe = Instant::now();
+            breaker.changed = true;
+        }
+    }
+
+    pub fn state(&self) -> DeviceState {
+        self.state.lock().unwrap().state
+    }
+
+    /// Returns the new state if it has changed since the last call.
+    pub fn take_change(&self) -> Option<DeviceState> {
+        let mut breaker = self.state.lock().unwrap();
+        match std::mem::take(&mut breaker.changed) {
+            true => Some(breaker.state),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Breaker, DeviceState, RetryPolicy};
+    use std::time::Duration;
+
+    #[test]
+    fn test_breaker() {
+        let breaker = Breaker::new(2, Duration::from_millis(20));
+
+        breaker.failure();
+        assert!(breaker.allow());
+        assert_eq!(None, breaker.take_change());
+
+        breaker.failure();
+        assert_eq!(Some(DeviceState::Offline), breaker.take_change());
+        assert!(!breaker.allow());
+
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+
+        breaker.success();
+        assert_eq!(Some(DeviceState::Online), breaker.take_change());
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            timeout: Duration::from_secs(1),
+            retries: 5,
+            backoff: Duration::from_millis(100),
+            backoff_max: Duration::from_millis(300),
+        };
+        assert_eq!(Duration::from_millis(100), policy.backoff(1));
+        assert_eq!(Duration::from_millis(200), policy.backoff(2));
+        assert_eq!(Duration::from_millis(300), policy.backoff(3));
+    }
+}
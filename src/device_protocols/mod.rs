@@ -1,15 +1,24 @@
 use crate::{
     gen_matcher,
     models::{
-        device::{ReadError, ReadFrequency, WriteError},
+        aggregation::Aggregate,
+        cache,
+        counter::{self, Counter, CounterOutput},
+        deadband::ReportPolicy,
+        device::{DeviceError, ReadFrequency},
+        metrics,
         tag::{TagResponse, TagValue},
     },
 };
 
+pub mod bus;
+pub mod health;
 pub mod modbus;
+pub mod safety;
+pub mod virtual_tags;
 
 macro_rules! get_config_folders {
-    ($pub_or:vis enum $e_name:ident { $( $variant:ident( $($types:ty),+ ) : config_folder: $config_folder:literal, reader: $reader:expr ),*, }) => {
+    ($pub_or:vis enum $e_name:ident { $( $variant:ident( $($types:ty),+ ) : config_folder: $config_folder:literal $(($optional:ident))?, reader: $reader:expr ),*, }) => {
         #[derive(Debug, Clone)]
         $pub_or enum $e_name {
             $( $variant($($types),+) ),*
@@ -20,10 +29,22 @@ macro_rules! get_config_folders {
                 vec![$( $config_folder ),*,]
             }
 
+            // Folders marked as `(optional)` may not exist.
+            fn optional_folders() -> Vec<&'static str> {
+                let mut folders = Vec::new();
+                $( $( let _ = stringify!($optional); folders.push($config_folder); )? )*
+                folders
+            }
+
             pub fn from_ini_files() -> Vec<$e_name> {
                 let mut tags = Vec::new();
                 for folder in $e_name::config_folders() {
-                    for device in std::fs::read_dir(folder).unwrap_or_else(|_| panic!("The folder {} cannot be found.", folder)) {
+                    let devices = match std::fs::read_dir(folder) {
+                        Ok(devices) => devices,
+                        Err(_) if $e_name::optional_folders().contains(&folder) => continue,
+                        Err(_) => panic!("The folder {} cannot be found.", folder),
+                    };
+                    for device in devices {
                         let protocol = &folder[..folder.len()];
 
                         let device_folder = device.unwrap().path();
@@ -54,53 +75,446 @@ gen_matcher!(
     }
 );
 
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use bus::{Bus, Priority};
+use health::{Breaker, DeviceState, RetryPolicy};
+use safety::{Limits, List};
+use std::future::Future;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+use tracing::{Instrument, Span};
+// The virtual tags never fail, so their device is always online.
+static VIRTUAL_BREAKER: LazyLock<Breaker> =
+    LazyLock::new(|| Breaker::new(1, Duration::from_secs(60)));
+
 get_config_folders!(
     pub enum DeviceProtocols {
-        ModbusTCP(modbus::tcp::Connection, modbus::tcp::Tag) : config_folder: "modbus_tcp", reader: modbus::tcp::reader,
-        ModbusRTUOverTCP(Arc<Mutex<modbus::rtu_over_tcp::Gateway>>, modbus::rtu_over_tcp::Connection, modbus::rtu_over_tcp::Tag)
+        ModbusTCP(Arc<Bus>, Arc<Breaker>, modbus::tcp::Connection, modbus::tcp::Tag) : config_folder: "modbus_tcp", reader: modbus::tcp::reader,
+        ModbusRTUOverTCP(Arc<Bus>, Arc<Breaker>, modbus::rtu_over_tcp::Gateway, modbus::rtu_over_tcp::Connection, modbus::rtu_over_tcp::Tag)
             : config_folder: "modbus_rtu_over_tcp", reader: modbus::rtu_over_tcp::reader,
+        Virtual(virtual_tags::Connection, virtual_tags::Tag) : config_folder: "virtual" (optional), reader: virtual_tags::reader,
     }
 );
 
+async fn with_timeout<T>(
+    duration: Duration,
+    request: impl Future<Output = Result<T, DeviceError>>,
+) -> Result<T, DeviceError> {
+    tokio::time::timeout(duration, request)
+        .await
+        .unwrap_or(Err(DeviceError::Timeout(duration)))
+}
+
+fn log_result<T>(result: Result<T, DeviceError>) -> Result<T, DeviceError> {
+    if let Err(err) = &result {
+        tracing::warn!(code = err.code(), error = %err, "Request failed");
+    }
+    result
+}
+
 impl DeviceProtocols {
-    pub async fn read(&self) -> Result<TagResponse, ReadError> {
+    async fn read_once(
+        &self,
+        priority: Priority,
+        timeout: Duration,
+    ) -> Result<TagResponse, DeviceError> {
+        let request = async {
+            match self {
+                DeviceProtocols::ModbusRTUOverTCP(bus, _, gw, c, t) => {
+                    let request = modbus::rtu_over_tcp::read(gw, c, t);
+                    bus.run(priority, with_timeout(timeout, request)).await
+                }
+                DeviceProtocols::ModbusTCP(bus, _, c, t) => {
+                    let request = modbus::tcp::read(c, t);
+                    bus.run(priority, with_timeout(timeout, request)).await
+                }
+                DeviceProtocols::Virtual(c, t) => virtual_tags::read(c, t),
+            }
+        };
+        let span = self.span("read");
+        async { self.record("read", request).await }
+            .instrument(span)
+            .await
+    }
+
+    pub async fn read(&self, priority: Priority) -> Result<TagResponse, DeviceError> {
+        // Virtual tags are computed from the cache, without a device to retry
+        // or to mark as offline.
+        let result = match self {
+            DeviceProtocols::Virtual(c, t) => virtual_tags::read(c, t),
+            _ => self.read_device(priority).await,
+        };
+        match &result {
+            Ok(response) => cache::update(response, self.valid_for()),
+            Err(_) => cache::mark_failed(&self.id()),
+        }
+        result
+    }
+
+    async fn read_device(&self, priority: Priority) -> Result<TagResponse, DeviceError> {
+        let (breaker, policy) = (self.breaker(), self.retry_policy());
+
+        // The retries are part of the same request, so the breaker counts one
+        // failure when all of them have failed.
+        if !breaker.allow() {
+            return Err(DeviceError::Offline(self.device_name()));
+        }
+        let mut attempt = 0;
+        let response = loop {
+            match self.read_once(priority, policy.timeout).await {
+                Ok(response) => {
+                    breaker.success();
+                    break response;
+                }
+                Err(err) => {
+                    if attempt >= policy.retries {
+                        breaker.failure();
+                        return Err(err);
+                    }
+                    attempt += 1;
+                    tokio::time::sleep(policy.backoff(attempt)).await;
+                }
+            }
+        };
+        // Only the polls sample the counters, at the frequency of the device.
+        Ok(match (self.counter(), priority) {
+            (Some(counter), Priority::Poll) => counter::apply(&counter, response),
+            (Some(counter), Priority::Command) => counter::last(&counter, response),
+            (None, _) => response,
+        })
+    }
+
+    /// Returns the cached value of the tag when it is newer than the
+    /// cache_max_age_ms of the device, otherwise, or with `force`, reads it.
+    pub async fn read_cached(
+        &self,
+        priority: Priority,
+        force: bool,
+    ) -> Result<TagResponse, DeviceError> {
+        let max_age = self.cache_max_age();
+        if !force && !max_age.is_zero() {
+            if let Some(response) = cache::get_fresh(&self.id(), max_age) {
+                return Ok(response);
+            }
+        }
+        self.read(priority).await
+    }
+
+    /// Parses the value of a write command according to the tag type.
+    pub fn parse_value(&self, text: &str) -> Result<TagValue, DeviceError> {
         match self {
-            DeviceProtocols::ModbusRTUOverTCP(gw, c, t) => {
-                modbus::rtu_over_tcp::read(&*gw.lock().await, c, t).await
+            DeviceProtocols::ModbusRTUOverTCP(_, _, _, _, t) => {
+                modbus::shared::parse_value(text, &t.command, t.length)
+            }
+            DeviceProtocols::ModbusTCP(_, _, _, t) => {
+                modbus::shared::parse_value(text, &t.command, t.length)
+            }
+            DeviceProtocols::Virtual(_, _) => Err(self.read_only()),
+        }
+    }
+
+    fn read_only(&self) -> DeviceError {
+        DeviceError::Config(format!("The tag {} is read only.", self.id()))
+    }
+
+    fn check_write(&self, value: &TagValue) -> Result<Limits, DeviceError> {
+        if self.mode() == Mode::Read {
+            return Err(self.read_only());
+        }
+        let limits = self.limits();
+        safety::check_write(safety::policy(), &self.id(), value, &limits)?;
+        Ok(limits)
+    }
+
+    /// First step of a select before operate write, the same value must be
+    /// written before the selection expires.
+    pub fn select(&self, value: TagValue) -> Result<(), DeviceError> {
+        let window = match self.check_write(&value)?.select_before_operate {
+            Some(window) => window,
+            None => {
+                return Err(DeviceError::Config(format!(
+                    "The tag {} does not need to be selected.",
+                    self.id()
+                )))
+            }
+        };
+        safety::select(&self.id(), value, window);
+        Ok(())
+    }
+
+    /// Checks the write against the safety policy of the tag, consuming its
+    /// selection when it needs select before operate.
+    pub fn authorize_write(&self, value: &TagValue) -> Result<(), DeviceError> {
+        if self.check_write(value)?.select_before_operate.is_some() {
+            safety::operate(&self.id(), value)?;
+        }
+        Ok(())
+    }
+
+    pub async fn write(&self, value: TagValue) -> Result<(), DeviceError> {
+        self.authorize_write(&value)?;
+        self.write_authorized(value).await
+    }
+
+    /// Writes a value already checked by `authorize_write` on the device.
+    pub async fn write_authorized(&self, value: TagValue) -> Result<(), DeviceError> {
+        let written = value.to_owned();
+        let (breaker, timeout) = (self.breaker(), self.retry_policy().timeout);
+        if !breaker.allow() {
+            return Err(DeviceError::Offline(self.device_name()));
+        }
+
+        let request = async {
+            match self {
+                DeviceProtocols::ModbusRTUOverTCP(bus, _, gw, c, t) => {
+                    let request = modbus::rtu_over_tcp::write(gw, c, t, value);
+                    bus.run(Priority::Command, with_timeout(timeout, request))
+                        .await
+                }
+                DeviceProtocols::ModbusTCP(bus, _, c, t) => {
+                    let request = modbus::tcp::write(c, t, value);
+                    bus.run(Priority::Command, with_timeout(timeout, request))
+                        .await
+                }
+                DeviceProtocols::Virtual(_, _) => Err(self.read_only()),
             }
-            DeviceProtocols::ModbusTCP(c, t) => modbus::tcp::read(c, t).await,
+        };
+        let span = self.span("write");
+        let result = async { self.record("write", request).await }
+            .instrument(span)
+            .await;
+        match result {
+            // The device answered, although it did not keep the value.
+            Ok(_) | Err(DeviceError::WriteMismatch(_, _)) => breaker.success(),
+            // The request was refused before reaching the device.
+            Err(DeviceError::Config(_) | DeviceError::InvalidCommand(_)) => {}
+            Err(_) => breaker.failure(),
+        }
+        // The cache holds the value the device has now, so the reads served
+        // from it do not return the one overwritten.
+        let held = match &result {
+            Ok(_) => Some(written),
+            Err(DeviceError::WriteMismatch(_, actual)) => Some(actual.to_owned()),
+            Err(_) => None,
+        };
+        if let Some(value) = held {
+            let response = TagResponse {
+                id: self.id(),
+                value,
+                cached: false,
+                timestamp: None,
+            };
+            cache::update(&response, self.valid_for());
         }
+        result
+    }
+
+    // Runs the request, logging its failure and counting it in the metrics.
+    async fn record<T>(
+        &self,
+        operation: &'static str,
+        request: impl Future<Output = Result<T, DeviceError>>,
+    ) -> Result<T, DeviceError> {
+        let started = Instant::now();
+        let result = log_result(request.await);
+        metrics::record_request(
+            &self.device_name(),
+            operation,
+            started.elapsed(),
+            result.is_ok(),
+        );
+        result
     }
 
-    pub async fn write(&self, value: TagValue) -> Result<(), WriteError> {
+    // Span of the requests to the tag, it gives the device context to their logs.
+    fn span(&self, operation: &'static str) -> Span {
+        let slave = match self {
+            DeviceProtocols::ModbusRTUOverTCP(_, _, _, c, _) => c.slave,
+            DeviceProtocols::ModbusTCP(_, _, c, _) => c.slave,
+            // Virtual tags are not requested to any slave.
+            DeviceProtocols::Virtual(_, _) => 0,
+        };
+        tracing::info_span!(
+            "modbus_request",
+            operation,
+            device = %self.device_name(),
+            tag = %self.tag_name(),
+            slave
+        )
+    }
+
+    fn limits(&self) -> Limits {
+        let (min, max, allowed_values, select_s) = match self {
+            DeviceProtocols::ModbusRTUOverTCP(_, _, _, _, t) => {
+                (t.min, t.max, &t.allowed_values, t.select_before_operate_s)
+            }
+            DeviceProtocols::ModbusTCP(_, _, _, t) => {
+                (t.min, t.max, &t.allowed_values, t.select_before_operate_s)
+            }
+            DeviceProtocols::Virtual(_, _) => (f32::MIN, f32::MAX, &List(Vec::new()), 0),
+        };
+        Limits {
+            min,
+            max,
+            allowed_values: allowed_values.0.to_owned(),
+            select_before_operate: match select_s {
+                0 => None,
+                seconds => Some(Duration::from_secs(seconds)),
+            },
+        }
+    }
+
+    fn breaker(&self) -> &Breaker {
         match self {
-            DeviceProtocols::ModbusRTUOverTCP(gw, c, t) => {
-                modbus::rtu_over_tcp::write(&*gw.lock().await, c, t, value).await
+            DeviceProtocols::ModbusRTUOverTCP(_, b, _, _, _) => b,
+            DeviceProtocols::ModbusTCP(_, b, _, _) => b,
+            DeviceProtocols::Virtual(_, _) => &VIRTUAL_BREAKER,
+        }
+    }
+
+    pub fn state(&self) -> DeviceState {
+        self.breaker().state()
+    }
+
+    /// Returns the new state of the device if it has changed since the last call.
+    pub fn take_state_change(&self) -> Option<DeviceState> {
+        self.breaker().take_change()
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        let (timeout_ms, retries, backoff_ms, backoff_max_ms) = match self {
+            DeviceProtocols::ModbusRTUOverTCP(_, _, _, c, _) => {
+                (c.timeout_ms, c.retries, c.backoff_ms, c.backoff_max_ms)
+            }
+            DeviceProtocols::ModbusTCP(_, _, c, _) => {
+                (c.timeout_ms, c.retries, c.backoff_ms, c.backoff_max_ms)
             }
-            DeviceProtocols::ModbusTCP(c, t) => modbus::tcp::write(c, t, value).await,
+            DeviceProtocols::Virtual(_, _) => (0, 0, 0, 0),
+        };
+        RetryPolicy {
+            timeout: Duration::from_millis(timeout_ms),
+            retries,
+            backoff: Duration::from_millis(backoff_ms),
+            backoff_max: Duration::from_millis(backoff_max_ms),
         }
     }
 
+    /// Window and functions of the tag when its values are published aggregated.
+    pub fn aggregation(&self) -> Option<(Duration, Vec<Aggregate>)> {
+        let (window_s, aggregate) = match self {
+            DeviceProtocols::ModbusRTUOverTCP(_, _, _, _, t) => {
+                (t.aggregate_window_s, &t.aggregate)
+            }
+            DeviceProtocols::ModbusTCP(_, _, _, t) => (t.aggregate_window_s, &t.aggregate),
+            DeviceProtocols::Virtual(_, t) => (t.aggregate_window_s, &t.aggregate),
+        };
+        match window_s {
+            0 => None,
+            seconds => Some((Duration::from_secs(seconds), aggregate.0.to_owned())),
+        }
+    }
+
+    /// Report by exception policy of the tag, if it is not published every read.
+    pub fn report_policy(&self) -> Option<ReportPolicy> {
+        let (on_change, deadband, deadband_type, heartbeat_min) = match self {
+            DeviceProtocols::ModbusRTUOverTCP(_, _, _, _, t) => (
+                t.publish_on_change,
+                t.deadband,
+                &t.deadband_type,
+                t.heartbeat_min,
+            ),
+            DeviceProtocols::ModbusTCP(_, _, _, t) => (
+                t.publish_on_change,
+                t.deadband,
+                &t.deadband_type,
+                t.heartbeat_min,
+            ),
+            DeviceProtocols::Virtual(_, t) => (
+                t.publish_on_change,
+                t.deadband,
+                &t.deadband_type,
+                t.heartbeat_min,
+            ),
+        };
+        if !on_change {
+            return None;
+        }
+        Some(ReportPolicy {
+            deadband,
+            deadband_type: deadband_type.to_owned(),
+            heartbeat: match heartbeat_min {
+                0 => None,
+                minutes => Some(Duration::from_secs(minutes * 60)),
+            },
+        })
+    }
+
+    /// Counter settings of the tag, if its value is a counter.
+    pub fn counter(&self) -> Option<Counter> {
+        let (bits, multiplier, output) = match self {
+            DeviceProtocols::ModbusRTUOverTCP(_, _, _, _, t) => {
+                (t.counter_bits, t.multiplier, &t.counter_output)
+            }
+            DeviceProtocols::ModbusTCP(_, _, _, t) => {
+                (t.counter_bits, t.multiplier, &t.counter_output)
+            }
+            DeviceProtocols::Virtual(_, _) => (0, 1.0, &CounterOutput::Total),
+        };
+        match bits {
+            0 => None,
+            bits => Some(Counter {
+                bits,
+                multiplier: modbus::shared::exact_multiplier(multiplier),
+                output: output.to_owned(),
+            }),
+        }
+    }
+
+    /// Age after which the last value read is not used by the virtual tags:
+    /// the cache_max_age_ms of the device or, without it, two read periods, so
+    /// a late poll is not taken as an outdated value.
+    fn valid_for(&self) -> Duration {
+        match self {
+            DeviceProtocols::Virtual(_, _) => Duration::MAX,
+            _ if !self.cache_max_age().is_zero() => self.cache_max_age(),
+            _ => Duration::from_secs(2 * self.freq().to_seconds()),
+        }
+    }
+
+    fn cache_max_age(&self) -> Duration {
+        let max_age_ms = match self {
+            DeviceProtocols::ModbusRTUOverTCP(_, _, _, c, _) => c.cache_max_age_ms,
+            DeviceProtocols::ModbusTCP(_, _, c, _) => c.cache_max_age_ms,
+            DeviceProtocols::Virtual(_, _) => 0,
+        };
+        Duration::from_millis(max_age_ms)
+    }
+
     pub fn tag_name(&self) -> String {
         match self {
-            DeviceProtocols::ModbusRTUOverTCP(_, _, t) => t.name.to_owned(),
-            DeviceProtocols::ModbusTCP(_, t) => t.name.to_owned(),
+            DeviceProtocols::ModbusRTUOverTCP(_, _, _, _, t) => t.name.to_owned(),
+            DeviceProtocols::ModbusTCP(_, _, _, t) => t.name.to_owned(),
+            DeviceProtocols::Virtual(_, t) => t.name.to_owned(),
         }
     }
 
     pub fn device_name(&self) -> String {
         match self {
-            DeviceProtocols::ModbusRTUOverTCP(_, c, _) => c.name.to_owned(),
-            DeviceProtocols::ModbusTCP(c, _) => c.name.to_owned(),
+            DeviceProtocols::ModbusRTUOverTCP(_, _, _, c, _) => c.name.to_owned(),
+            DeviceProtocols::ModbusTCP(_, _, c, _) => c.name.to_owned(),
+            DeviceProtocols::Virtual(c, _) => c.name.to_owned(),
         }
     }
 
+    /// Fully qualified name of the tag, the same used as id in the TagResponse.
+    pub fn id(&self) -> String {
+        format!("{}/{}", self.device_name(), self.tag_name())
+    }
+
     pub fn mode(&self) -> Mode {
         match self {
-            DeviceProtocols::ModbusRTUOverTCP(_, _, t) => t.mode.to_owned(),
-            DeviceProtocols::ModbusTCP(_, t) => t.mode.to_owned(),
+            DeviceProtocols::ModbusRTUOverTCP(_, _, _, _, t) => t.mode.to_owned(),
+            DeviceProtocols::ModbusTCP(_, _, _, t) => t.mode.to_owned(),
+            DeviceProtocols::Virtual(_, _) => Mode::Read,
         }
     }
 
@@ -111,8 +525,43 @@ impl DeviceProtocols {
 
     pub fn freq(&self) -> ReadFrequency {
         match self {
-            DeviceProtocols::ModbusRTUOverTCP(_, c, _) => c.read_freq.to_owned(),
-            DeviceProtocols::ModbusTCP(c, _) => c.read_freq.to_owned(),
+            DeviceProtocols::ModbusRTUOverTCP(_, _, _, c, _) => c.read_freq.to_owned(),
+            DeviceProtocols::ModbusTCP(_, _, c, _) => c.read_freq.to_owned(),
+            // Not polled, see dependencies.
+            DeviceProtocols::Virtual(_, _) => ReadFrequency::Seconds(0),
+        }
+    }
+
+    /// Ids of the tags a virtual tag is computed from, it is evaluated after
+    /// they are read. None for the tags read from a device.
+    pub fn dependencies(&self) -> Option<Vec<String>> {
+        match self {
+            DeviceProtocols::Virtual(_, t) => Some(t.expression.dependencies()),
+            _ => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn test_retries_count_one_failure() {
+        use super::health::{Breaker, DeviceState};
+        use super::{modbus::shared::Command, DeviceProtocols, Mode, Priority};
+        use crate::cloud_protocols::mqtt::tests::unreachable_device;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let mut dev = unreachable_device("Power", Command::Holding, Mode::Read);
+        let breaker = Arc::new(Breaker::new(2, Duration::from_secs(60)));
+        if let DeviceProtocols::ModbusTCP(_, b, connection, _) = &mut dev {
+            *b = breaker.clone();
+            connection.retries = 2;
+        }
+
+        assert!(dev.read(Priority::Poll).await.is_err());
+        assert_eq!(DeviceState::Online, breaker.state());
+        assert!(dev.read(Priority::Poll).await.is_err());
+        assert_eq!(DeviceState::Offline, breaker.state());
+    }
+}
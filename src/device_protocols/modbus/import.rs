@@ -0,0 +1,167 @@
+use super::shared;
+use crate::device_protocols::Mode;
+use ini::Ini;
+use std::collections::HashSet;
+
+// Accepted names for every column of the register map, the first one is the
+// key written to the generated ini file.
+const COLUMNS: [&[&str]; 9] = [
+    &["name"],
+    &["address"],
+    &["length"],
+    &["command", "function"],
+    &["data_type", "type"],
+    &["swap", "byte_order"],
+    &["multiplier", "scale"],
+    &["unit"],
+    &["mode"],
+];
+
+const REQUIRED_COLUMNS: [&str; 6] = ["name", "address", "length", "command", "data_type", "swap"];
+
+fn column_key(header: &str) -> Option<&'static str> {
+    let header = header.trim().to_lowercase().replace(' ', "_");
+    COLUMNS
+        .iter()
+        .find(|aliases| aliases.contains(&header.as_str()))
+        .map(|aliases| aliases[0])
+}
+
+fn validate_row(row: &[(&'static str, String)]) -> Result<(), String> {
+    let get = |key: &str| {
+        row.iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+            .unwrap_or_default()
+    };
+    let check = |key: &str, valid: bool| match valid {
+        true => Ok(()),
+        false => Err(format!("Invalid {} \"{}\".", key, get(key))),
+    };
+
+    check("name", !get("name").is_empty())?;
+    check("address", get("address").parse::<u16>().is_ok())?;
+    check("length", get("length").parse::<u16>().is_ok())?;
+    check("command", get("command").parse::<shared::Command>().is_ok())?;
+    check(
+        "data_type",
+        get("data_type").parse::<shared::Type>().is_ok(),
+    )?;
+    check("swap", get("swap").parse::<shared::Swap>().is_ok())?;
+    check("multiplier", get("multiplier").parse::<f32>().is_ok())?;
+    check("mode", get("mode").parse::<Mode>().is_ok())
+}
+
+/// Reads a CSV register map and generates the equivalent tags ini. Every invalid
+/// row is reported with its line number.
+pub fn register_map_to_ini<R: std::io::Read>(csv_data: R) -> Result<Ini, Vec<String>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(csv_data);
+
+    let headers = reader.headers().map_err(|err| vec![err.to_string()])?;
+    let keys: Vec<Option<&'static str>> = headers.iter().map(column_key).collect();
+    let missing: Vec<String> = REQUIRED_COLUMNS
+        .iter()
+        .filter(|column| !keys.contains(&Some(column)))
+        .map(|column| format!("The column {} cannot be found.", column))
+        .collect();
+    if !missing.is_empty() {
+        return Err(missing);
+    }
+
+    let mut ini = Ini::new();
+    let mut errors = Vec::new();
+    let mut names = HashSet::new();
+
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(err) => {
+                errors.push(err.to_string());
+                continue;
+            }
+        };
+        let line = record.position().map(|p| p.line()).unwrap_or_default();
+
+        let mut row: Vec<(&'static str, String)> = keys
+            .iter()
+            .zip(record.iter())
+            .filter_map(|(key, value)| key.map(|key| (key, value.to_string())))
+            .filter(|(_, value)| !value.is_empty())
+            .collect();
+        if !row.iter().any(|(k, _)| *k == "multiplier") {
+            row.push(("multiplier", "1".to_string()));
+        }
+        if !row.iter().any(|(k, _)| *k == "mode") {
+            row.push(("mode", "Read".to_string()));
+        }
+
+        if let Err(err) = validate_row(&row) {
+            errors.push(format!("Line {}: {}", line, err));
+            continue;
+        }
+
+        let (_, name) = row.iter().find(|(k, _)| *k == "name").unwrap().to_owned();
+        if !names.insert(name.to_owned()) {
+            errors.push(format!("Line {}: Duplicated name \"{}\".", line, name));
+            continue;
+        }
+
+        for (key, value) in row.into_iter().filter(|(k, _)| *k != "name") {
+            ini.set_to(Some(name.as_str()), key.to_string(), value);
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(ini),
+        false => Err(errors),
+    }
+}
+
+/// Generates the ini file `output` from the CSV register map `csv_file`.
+pub fn import_register_map(csv_file: &str, output: &str) -> Result<(), Vec<String>> {
+    let csv_data =
+        std::fs::File::open(csv_file).map_err(|err| vec![format!("{}: {}", csv_file, err)])?;
+    register_map_to_ini(csv_data)?
+        .write_to_file(output)
+        .map_err(|err| vec![format!("{}: {}", output, err)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::register_map_to_ini;
+
+    #[test]
+    fn test_register_map_to_ini() {
+        let csv = "Name,Address,Length,Function,Data Type,Byte Order,Scale,Unit\n\
+                   Tension_R,7,2,Holding,Float,BigEndian,,V\n\
+                   Corriente,9,2,Holding,Double,BigEndian,0.1,A\n\
+                   Potencia,eleven,2,Holding,Integer,BigEndian,1,W\n\
+                   Tension_R,13,2,Holding,Float,BigEndian,1,V\n";
+
+        let errors = register_map_to_ini(csv.as_bytes()).err().unwrap();
+        assert_eq!(
+            vec![
+                "Line 3: Invalid data_type \"Double\".",
+                "Line 4: Invalid address \"eleven\".",
+                "Line 5: Duplicated name \"Tension_R\".",
+            ],
+            errors
+        );
+
+        let ini = register_map_to_ini(
+            csv.lines()
+                .take(2)
+                .collect::<Vec<_>>()
+                .join("\n")
+                .as_bytes(),
+        )
+        .unwrap();
+        let section = ini.section(Some("Tension_R")).unwrap();
+        assert_eq!(Some("Holding"), section.get("command"));
+        assert_eq!(Some("1"), section.get("multiplier"));
+        assert_eq!(Some("Read"), section.get("mode"));
+        assert_eq!(Some("V"), section.get("unit"));
+    }
+}
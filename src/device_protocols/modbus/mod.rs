@@ -1,3 +1,4 @@
+pub mod import;
 pub mod rtu_over_tcp;
-mod shared;
+pub mod shared;
 pub mod tcp;
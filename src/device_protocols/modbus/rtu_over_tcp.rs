@@ -1,17 +1,23 @@
 use tokio_modbus::{client::Context, prelude::*};
 
+use crate::device_protocols::bus::Bus;
+use crate::device_protocols::health::Breaker;
+use crate::device_protocols::safety::List;
 use crate::gen_readable_struct;
+use crate::models::aggregation::Aggregate;
+use crate::models::counter::CounterOutput;
+use crate::models::deadband::DeadbandType;
 use crate::DeviceProtocols;
 
 use super::shared;
 
-const SLEEP_SECONDS_CONVERTER_NEEDS_TO_HANDLE_NEW_REQUEST: u64 = 1;
-
 gen_readable_struct!(
     struct Gateway {
         name: String,
         ip: std::net::IpAddr,
         port: u16,
+        max_concurrent_requests: usize = 1,
+        inter_request_delay_ms: u64 = 1000,
     }
 );
 
@@ -20,6 +26,14 @@ gen_readable_struct!(
         name: String,
         slave: u8,
         read_freq: ReadFrequency,
+        profile: String = String::new(),
+        timeout_ms: u64 = 4000,
+        retries: u32 = 0,
+        backoff_ms: u64 = 500,
+        backoff_max_ms: u64 = 5000,
+        breaker_failures: u32 = 3,
+        breaker_probe_s: u64 = 60,
+        cache_max_age_ms: u64 = 0,
     }
 );
 
@@ -33,20 +47,40 @@ gen_readable_struct!(
         data_type: shared::Type,
         mode: super::super::Mode,
         multiplier: f32,
+        min: f32 = f32::MIN,
+        max: f32 = f32::MAX,
+        allowed_values: List<f32> = List::default(),
+        select_before_operate_s: u64 = 0,
+        verify_write: bool = false,
+        verify_delay_ms: u64 = 100,
+        write_function: shared::WriteFunction = shared::WriteFunction::Auto,
+        write_mask: u16 = 65535,
+        aggregate_window_s: u64 = 0,
+        aggregate: List<Aggregate> = List::default(),
+        publish_on_change: bool = false,
+        deadband: f32 = 0.0,
+        deadband_type: DeadbandType = DeadbandType::Absolute,
+        heartbeat_min: u64 = 0,
+        counter_bits: u32 = 0,
+        counter_output: CounterOutput = CounterOutput::Total,
     }
 );
 
 use crate::config_files::ini_parser;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
 pub fn reader<F>(constructor: F, path: &str) -> Vec<DeviceProtocols>
 where
-    F: Fn(Arc<Mutex<Gateway>>, Connection, Tag) -> DeviceProtocols,
+    F: Fn(Arc<Bus>, Arc<Breaker>, Gateway, Connection, Tag) -> DeviceProtocols,
 {
     let mut rtu_devices_under_same_gw = Vec::new();
-    let gateway = Arc::new(Mutex::new(
-        ini_parser::read_file::<Gateway>(&(format!("{}/connection.ini", path)))[0].to_owned(),
-    ));
+    let gateway =
+        ini_parser::read_file::<Gateway>(&(format!("{}/connection.ini", path)))[0].to_owned();
+    let bus = Bus::for_endpoint(
+        &format!("{}:{}", gateway.ip, gateway.port),
+        gateway.max_concurrent_requests,
+        Duration::from_millis(gateway.inter_request_delay_ms),
+    );
 
     for device_folder in
         std::fs::read_dir(path).unwrap_or_else(|_| panic!("The folder {} cannot be found.", &path))
@@ -60,58 +94,63 @@ where
         let connection =
             ini_parser::read_file::<Connection>(&(format!("{}/connection.ini", &path)))[0]
                 .to_owned();
-
-        ini_parser::read_file::<Tag>(&(format!("{}/publishers.ini", &path)))
-            .iter()
-            .for_each(|tag| {
-                rtu_devices_under_same_gw.push(constructor(
-                    gateway.to_owned(),
-                    connection.to_owned(),
-                    tag.to_owned(),
-                ))
-            });
+        let breaker = Arc::new(Breaker::new(
+            connection.breaker_failures,
+            Duration::from_secs(connection.breaker_probe_s),
+        ));
+
+        ini_parser::read_file_with_profile::<Tag>(
+            &connection.profile,
+            &(format!("{}/publishers.ini", &path)),
+        )
+        .iter()
+        .for_each(|tag| {
+            rtu_devices_under_same_gw.push(constructor(
+                bus.to_owned(),
+                breaker.to_owned(),
+                gateway.to_owned(),
+                connection.to_owned(),
+                tag.to_owned(),
+            ))
+        });
     }
     rtu_devices_under_same_gw
 }
 
-use crate::models::device::{ReadError, ReadFrequency, WriteError};
+use crate::models::device::{DeviceError, ReadFrequency};
 use crate::models::tag::{TagResponse, TagValue};
 
-#[derive(Debug, Clone)]
-struct Error(String);
-
-async fn connect(gw: &Gateway, con: &Connection) -> Result<Context, Error> {
-    let Gateway { ip, port, .. } = gw.to_owned();
+async fn connect(gw: &Gateway, con: &Connection) -> Result<Context, DeviceError> {
+    let Gateway { name, ip, port, .. } = gw.to_owned();
     let Connection { slave, .. } = con.to_owned();
 
     let ethernet_gateway = tokio::net::TcpStream::connect((ip, port))
         .await
-        .map_err(|err| Error(err.to_string()))?;
+        .map_err(|err| DeviceError::Transport(format!("{}: {}", name, err)))?;
 
     match rtu::connect_slave(ethernet_gateway, Slave(slave)).await {
         Ok(ctx) => Ok(ctx),
-        Err(err) => Err(Error(err.to_string())),
+        Err(err) => Err(DeviceError::Transport(err.to_string())),
     }
 }
 
-pub async fn read(gw: &Gateway, con: &Connection, tag: &Tag) -> Result<TagResponse, ReadError> {
-    let mut ctx = connect(gw, con).await.map_err(|err| ReadError(err.0))?;
+pub async fn read(gw: &Gateway, con: &Connection, tag: &Tag) -> Result<TagResponse, DeviceError> {
+    let mut ctx = connect(gw, con).await?;
 
     let raw_data = shared::read(&mut ctx, &tag.command, tag.address, tag.length).await?;
-    let parsed_data = shared::parse_readed(raw_data, &tag.swap, &tag.data_type, &tag.multiplier);
-
-    // Making this little sleep we block the handler during X time
-    // this time gives the cheaper devices some more time to handle
-    // the next request.
-    tokio::time::sleep(std::time::Duration::new(
-        SLEEP_SECONDS_CONVERTER_NEEDS_TO_HANDLE_NEW_REQUEST,
-        0,
-    ))
-    .await;
+    let parsed_data = shared::decode(
+        raw_data,
+        &tag.command,
+        &tag.swap,
+        &tag.data_type,
+        &tag.multiplier,
+    )?;
 
     Ok(TagResponse {
         id: format!("{}/{}", con.name, tag.name),
         value: parsed_data,
+        cached: false,
+        timestamp: None,
     })
 }
 
@@ -120,20 +159,42 @@ pub async fn write(
     con: &Connection,
     tag: &Tag,
     value: TagValue,
-) -> Result<(), WriteError> {
-    let mut ctx = connect(gw, con).await.map_err(|err| WriteError(err.0))?;
-
-    let value_to_write = shared::parse_write(&value, &tag.swap);
-    shared::write(&mut ctx, &tag.command, tag.address, &value_to_write).await?;
-
-    // Making this little sleep we block the handler during X time
-    // this time gives the cheaper devices some more time to handle
-    // the next request.
-    tokio::time::sleep(std::time::Duration::new(
-        SLEEP_SECONDS_CONVERTER_NEEDS_TO_HANDLE_NEW_REQUEST,
-        0,
-    ))
-    .await;
+) -> Result<(), DeviceError> {
+    let value_to_write = shared::encode_write(
+        &value,
+        &tag.command,
+        &tag.swap,
+        &tag.data_type,
+        tag.length,
+        tag.multiplier,
+    )?;
+    let function = shared::write_function(&tag.write_function, &tag.command, value_to_write.len())?;
+
+    let mut ctx = connect(gw, con).await?;
+    shared::write(
+        &mut ctx,
+        &function,
+        tag.address,
+        &value_to_write,
+        tag.write_mask,
+    )
+    .await?;
+
+    if tag.verify_write {
+        tokio::time::sleep(Duration::from_millis(tag.verify_delay_ms)).await;
+        let mut ctx = connect(gw, con).await?;
+        let length = value_to_write.len() as u16;
+        let read_back = shared::read(&mut ctx, &tag.command, tag.address, length).await?;
+        shared::check_read_back(
+            &value_to_write,
+            &read_back,
+            &tag.command,
+            &tag.swap,
+            &tag.data_type,
+            &tag.multiplier,
+            tag.write_mask,
+        )?;
+    }
 
     Ok(())
 }
@@ -1,10 +1,12 @@
-use crate::models::device::WriteError;
+use crate::gen_matcher;
+use crate::models::device::{DeviceError, ModbusException};
 use crate::models::tag::TagValue;
-use crate::{gen_matcher, models::device::ReadError};
-use tokio_modbus::client::Context;
-use tokio_modbus::prelude::{Reader, Writer};
+use std::time::Instant;
+use tokio_modbus::client::{Client, Context};
+use tokio_modbus::prelude::{Reader, Request, Writer};
 
 gen_matcher!(
+    #[allow(clippy::enum_variant_names)]
     enum Swap {
         BigEndian,
         LittleEndian,
@@ -29,29 +31,120 @@ gen_matcher!(
     }
 );
 
+gen_matcher!(
+    #[allow(clippy::upper_case_acronyms)]
+    enum WriteFunction {
+        Auto,
+        FC05,
+        FC06,
+        FC15,
+        FC16,
+        FC22,
+        FC23,
+    }
+);
+
+/// Returns an error if the registers of the command cannot be written.
+pub fn check_writable(command: &Command) -> Result<(), DeviceError> {
+    match command {
+        Command::Coil | Command::Holding => Ok(()),
+        Command::Discrete => Err(DeviceError::Config(
+            "A discrete register cannot be written.".to_string(),
+        )),
+        Command::Input => Err(DeviceError::Config(
+            "An input register cannot be written.".to_string(),
+        )),
+    }
+}
+
+/// Returns the function used to write `words` registers of the command, the
+/// `Auto` function uses FC05/FC15 for the coils and FC16 for the holdings.
+pub fn write_function(
+    function: &WriteFunction,
+    command: &Command,
+    words: usize,
+) -> Result<WriteFunction, DeviceError> {
+    check_writable(command)?;
+    let function = match (function, command) {
+        (WriteFunction::Auto, Command::Coil) if words == 1 => WriteFunction::FC05,
+        (WriteFunction::Auto, Command::Coil) => WriteFunction::FC15,
+        (WriteFunction::Auto, _) => WriteFunction::FC16,
+        (function, _) => function.to_owned(),
+    };
+
+    let valid = match function {
+        WriteFunction::FC05 => *command == Command::Coil && words == 1,
+        WriteFunction::FC15 => *command == Command::Coil,
+        WriteFunction::FC06 | WriteFunction::FC22 => *command == Command::Holding && words == 1,
+        WriteFunction::FC16 | WriteFunction::FC23 => *command == Command::Holding,
+        WriteFunction::Auto => false,
+    };
+    match valid {
+        true => Ok(function),
+        false => Err(DeviceError::Config(format!(
+            "The function {:?} cannot write {} {:?} registers.",
+            function, words, command
+        ))),
+    }
+}
+
+/// Writes the registers with the given function. The FC22 (mask write) only
+/// changes the bits of the register set in `mask`.
 pub async fn write(
     ctx: &mut Context,
-    command: &Command,
+    function: &WriteFunction,
     address: u16,
     value_to_write: &[u16],
-) -> Result<(), WriteError>
+    mask: u16,
+) -> Result<(), DeviceError>
 where
     Context: Writer,
 {
-    match command {
-        Command::Coil => {
-            ctx.write_single_coil(address, from_byte_slice_to_coil(&value_to_write))
+    let coils = || {
+        value_to_write
+            .iter()
+            .map(|&w| w != 0)
+            .collect::<Vec<bool>>()
+    };
+    let started = Instant::now();
+    let result = match function {
+        WriteFunction::FC05 => {
+            ctx.write_single_coil(address, from_byte_slice_to_coil(value_to_write))
                 .await
         }
-        Command::Discrete => unimplemented!("A discrete register cannot be written."),
-        Command::Holding => ctx.write_multiple_registers(address, &value_to_write).await,
-        Command::Input => unimplemented!("An input register cannot be written."),
+        WriteFunction::FC06 => ctx.write_single_register(address, value_to_write[0]).await,
+        WriteFunction::FC15 => ctx.write_multiple_coils(address, &coils()).await,
+        WriteFunction::FC22 => {
+            // tokio-modbus has no mask write request, so it is sent as a custom one.
+            let (and_mask, or_mask) = (!mask, value_to_write[0] & mask);
+            let data = [address, and_mask, or_mask]
+                .iter()
+                .flat_map(|word| word.to_be_bytes())
+                .collect();
+            ctx.call(Request::Custom(0x16, data)).await.map(|_| ())
+        }
+        WriteFunction::FC23 => {
+            let length = value_to_write.len() as u16;
+            ctx.read_write_multiple_registers(address, length, address, value_to_write)
+                .await
+                .map(|_| ())
+        }
+        WriteFunction::FC16 | WriteFunction::Auto => {
+            ctx.write_multiple_registers(address, value_to_write).await
+        }
     }
-    .map_err(|err| WriteError(err.to_string()))?;
+    .map_err(to_device_error);
+    let length = value_to_write.len() as u16;
+    log_request(
+        &format!("{:?}", function),
+        address,
+        length,
+        started,
+        &result,
+    );
+    result?;
 
-    ctx.disconnect()
-        .await
-        .map_err(|err| WriteError(err.to_string()))?;
+    ctx.disconnect().await.map_err(to_device_error)?;
     Ok(())
 }
 
@@ -60,10 +153,11 @@ pub async fn read(
     command: &Command,
     address: u16,
     length: u16,
-) -> Result<Vec<u16>, ReadError>
+) -> Result<Vec<u16>, DeviceError>
 where
     Context: Reader,
 {
+    let (function, started) = (read_function(command), Instant::now());
     let readed_data = match command {
         Command::Coil => from_coil_to_word(ctx.read_coils(address, length)),
         Command::Discrete => from_coil_to_word(ctx.read_discrete_inputs(address, length)),
@@ -71,16 +165,76 @@ where
         Command::Input => ctx.read_input_registers(address, length),
     };
 
-    let readed_data = readed_data
-        .await
-        .map_err(|err| ReadError(err.to_string()))?;
+    let readed_data = readed_data.await.map_err(to_device_error);
+    log_request(function, address, length, started, &readed_data);
+    let readed_data = readed_data?;
+
+    ctx.disconnect().await.map_err(to_device_error)?;
 
-    ctx.disconnect()
-        .await
-        .map_err(|err| ReadError(err.to_string()))?;
+    if readed_data.len() < length as usize {
+        return Err(DeviceError::Decode(format!(
+            "Expected {} values but {} were received.",
+            length,
+            readed_data.len()
+        )));
+    }
     Ok(readed_data)
 }
 
+fn read_function(command: &Command) -> &'static str {
+    match command {
+        Command::Coil => "FC01",
+        Command::Discrete => "FC02",
+        Command::Holding => "FC03",
+        Command::Input => "FC04",
+    }
+}
+
+fn log_request<T>(
+    function: &str,
+    address: u16,
+    length: u16,
+    started: Instant,
+    result: &Result<T, DeviceError>,
+) {
+    let duration_ms = started.elapsed().as_millis() as u64;
+    match result {
+        Ok(_) => tracing::debug!(
+            function,
+            address,
+            length,
+            duration_ms,
+            "Modbus request done"
+        ),
+        Err(err) => tracing::debug!(
+            function,
+            address,
+            length,
+            duration_ms,
+            error = %err,
+            "Modbus request failed"
+        ),
+    }
+}
+
+/// Converts the errors returned by tokio-modbus. Its exception type is private,
+/// so the exceptions of the device are recognized by the error that wraps it,
+/// of kind Other and displayed as "Modbus function {code}: {exception name}".
+pub fn to_device_error(err: std::io::Error) -> DeviceError {
+    let message = err.to_string();
+    let wrapped = err.kind() == std::io::ErrorKind::Other && err.get_ref().is_some();
+    let exception = message
+        .strip_prefix("Modbus function ")
+        .filter(|_| wrapped)
+        .and_then(|msg| msg.split_once(": "))
+        .and_then(|(_, name)| ModbusException::from_name(name));
+
+    match exception {
+        Some(exception) => DeviceError::Exception(exception),
+        None => DeviceError::Transport(message),
+    }
+}
+
 use core::future::Future;
 use core::pin::Pin;
 fn from_coil_to_word<'a>(
@@ -98,46 +252,219 @@ fn from_coil_to_word<'a>(
     })
 }
 
-pub fn parse_write(data: &TagValue, swap: &Swap) -> Vec<u16> {
-    let data = match data {
-        TagValue::F32(val) => val.to_be_bytes(),
-        TagValue::I32(val) => val.to_be_bytes(),
+/// Parses the text of a write command, `true/false/on/off/1/0` for a coil, an
+/// array of them (i.e. `[1, 0, on]`) or a bit mask for several coils and a number
+/// in engineering units for the registers.
+pub fn parse_value(text: &str, command: &Command, length: u16) -> Result<TagValue, DeviceError> {
+    let invalid =
+        || DeviceError::InvalidCommand(format!("The value \"{}\" cannot be parsed.", text));
+    let parse_bool = |text: &str| match text.trim().to_lowercase().as_str() {
+        "true" | "on" | "1" => Ok(true),
+        "false" | "off" | "0" => Ok(false),
+        _ => Err(invalid()),
     };
-    let data = data.map(|b| b as u16).to_vec();
-
-    match swap {
-        Swap::LittleEndian => data.iter().map(swap_bytes).rev().collect(),
-        Swap::BigEndian => data,
-        Swap::LittleEndianSwap => swap_words(data.iter().map(swap_bytes).rev().collect()),
-        Swap::BigEndianSwap => swap_words(data),
+    match command {
+        Command::Coil | Command::Discrete => {
+            if let Some(items) = text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+                let coils = items
+                    .split(',')
+                    .map(parse_bool)
+                    .collect::<Result<Vec<_>, _>>()?;
+                if coils.len() != length as usize || coils.len() > 32 {
+                    return Err(DeviceError::InvalidCommand(format!(
+                        "Expected {} coils but {} were received.",
+                        length,
+                        coils.len()
+                    )));
+                }
+                let mask = coils
+                    .iter()
+                    .rev()
+                    .fold(0u32, |acc, &coil| acc << 1 | coil as u32);
+                return Ok(TagValue::I32(mask as i32));
+            }
+            match parse_bool(text) {
+                Ok(coil) => Ok(TagValue::I32(coil as i32)),
+                Err(err) if length == 1 => Err(err),
+                Err(err) => text.parse().map(TagValue::I32).map_err(|_| err),
+            }
+        }
+        Command::Holding | Command::Input => match text.parse::<i32>() {
+            Ok(value) => Ok(TagValue::I32(value)),
+            Err(_) => text.parse().map(TagValue::F32).map_err(|_| invalid()),
+        },
     }
 }
 
-pub fn parse_readed(data: Vec<u16>, swap: &Swap, data_type: &Type, multiplier: &f32) -> TagValue {
-    let data: Vec<u16> = match swap {
-        Swap::LittleEndian => data.iter().map(swap_bytes).rev().collect(),
-        Swap::BigEndian => data,
-        Swap::LittleEndianSwap => swap_words(data.iter().map(swap_bytes).rev().collect()),
-        Swap::BigEndianSwap => swap_words(data),
+/// Converts a value in engineering units to the registers of the tag, removing
+/// the multiplier and checking that it fits in them.
+pub fn encode_write(
+    value: &TagValue,
+    command: &Command,
+    swap: &Swap,
+    data_type: &Type,
+    length: u16,
+    multiplier: f32,
+) -> Result<Vec<u16>, DeviceError> {
+    let out_of_range =
+        || DeviceError::InvalidCommand(format!("The value {} is out of range.", value));
+    // The coils are written from a bit mask, the first coil is the lowest bit.
+    if let Command::Coil = command {
+        let mask = match value {
+            TagValue::I32(x) if length < 32 && (*x as i64) < 1 << length && *x >= 0 => *x as u32,
+            TagValue::I32(x) if length == 32 => *x as u32,
+            _ => return Err(out_of_range()),
+        };
+        return Ok((0..length).map(|bit| (mask >> bit & 1) as u16).collect());
+    }
+
+    let raw = match value {
+        TagValue::I32(x) => *x as f64,
+        TagValue::F32(x) => *x as f64,
+        TagValue::F64(x) => *x,
+    } / multiplier as f64;
+
+    // The values of one register can be read as signed or unsigned.
+    let (min, max) = match (data_type, length) {
+        (_, 0) | (_, 3..) => {
+            return Err(DeviceError::Config(format!(
+                "A tag of {} registers cannot be written.",
+                length
+            )))
+        }
+        (Type::Integer, 1) => (i16::MIN as f64, u16::MAX as f64),
+        (Type::Integer, 2) => (i32::MIN as f64, u32::MAX as f64),
+        (Type::Float, 1) => {
+            return Err(DeviceError::Config(
+                "A float tag needs 2 registers.".to_string(),
+            ))
+        }
+        (Type::Float, 2) => (f32::MIN as f64, f32::MAX as f64),
     };
+    let raw = match data_type {
+        Type::Integer => raw.round(),
+        Type::Float => raw,
+    };
+    if !raw.is_finite() || raw < min || raw > max {
+        return Err(out_of_range());
+    }
+    Ok(to_registers(raw, swap, data_type, length))
+}
 
-    let data_as_string = match data_type {
-        Type::Integer => data
-            .iter()
-            .fold(0i32, |acc, &num| acc << 16 | num as i32)
-            .to_string(),
+/// Compares the registers read back after a write with the written ones.
+pub fn check_read_back(
+    written: &[u16],
+    read_back: &[u16],
+    command: &Command,
+    swap: &Swap,
+    data_type: &Type,
+    multiplier: &f32,
+    mask: u16,
+) -> Result<(), DeviceError> {
+    let masked = |data: &[u16]| data.iter().map(|word| word & mask).collect::<Vec<u16>>();
+    let (written, read_back) = (masked(written), masked(read_back));
+    if written == read_back {
+        return Ok(());
+    }
+    Err(DeviceError::WriteMismatch(
+        decode(written, command, swap, data_type, multiplier)?,
+        decode(read_back, command, swap, data_type, multiplier)?,
+    ))
+}
+
+/// Converts the registers read from a tag, the coils are returned as a bit mask
+/// with the first coil in the lowest bit.
+pub fn decode(
+    data: Vec<u16>,
+    command: &Command,
+    swap: &Swap,
+    data_type: &Type,
+    multiplier: &f32,
+) -> Result<TagValue, DeviceError> {
+    match command {
+        Command::Coil | Command::Discrete => Ok(TagValue::I32(
+            data.iter()
+                .rev()
+                .fold(0u32, |acc, &coil| acc << 1 | (coil != 0) as u32) as i32,
+        )),
+        Command::Holding | Command::Input => parse_readed(data, swap, data_type, multiplier),
+    }
+}
+
+pub fn parse_readed(
+    data: Vec<u16>,
+    swap: &Swap,
+    data_type: &Type,
+    multiplier: &f32,
+) -> Result<TagValue, DeviceError> {
+    let data = apply_swap(data, swap);
+
+    match data_type {
+        Type::Integer => {
+            let readed_value = data.iter().fold(0i32, |acc, &num| acc << 16 | num as i32);
+            Ok(scale_integer(readed_value, *multiplier))
+        }
         Type::Float => {
             let num = data.iter().fold(0u32, |acc, &num| acc << 16 | num as u32);
-            format!("{:.2}", f32::from_bits(num))
+            let readed_value: f32 =
+                format!("{:.2}", f32::from_bits(num)).parse().map_err(|_| {
+                    DeviceError::Decode(format!("The registers {:?} cannot be decoded.", data))
+                })?;
+            let scaled_value = readed_value * multiplier;
+            Ok(match is_integer(scaled_value) {
+                true => TagValue::I32(scaled_value as i32),
+                false => TagValue::F32(scaled_value),
+            })
         }
-    };
+    }
+}
 
-    let readed_value: f32 = data_as_string.parse().unwrap();
-    let scaled_value = readed_value * multiplier;
+/// Multiplier of the ini as the f64 of its decimal text, so 0.001 does not
+/// carry the error of the f32 into the scaled values.
+pub fn exact_multiplier(multiplier: f32) -> f64 {
+    multiplier.to_string().parse().unwrap_or(multiplier as f64)
+}
 
-    match is_integer(scaled_value) {
-        true => TagValue::I32(scaled_value as i32),
-        false => TagValue::F32(scaled_value),
+// Integers are scaled in f64, the counters keep counting above 2^24. The value
+// is an f32 only when it keeps every step of the multiplier.
+fn scale_integer(value: i32, multiplier: f32) -> TagValue {
+    let step = exact_multiplier(multiplier);
+    let scaled_value = value as f64 * step;
+    if scaled_value.fract() == 0.0 && scaled_value.abs() <= i32::MAX as f64 {
+        return TagValue::I32(scaled_value as i32);
+    }
+    // The gap between two f32 around the value is |value| * 2^-23.
+    match scaled_value.abs() * f32::EPSILON as f64 <= step.abs() {
+        true => TagValue::F32(scaled_value as f32),
+        false => TagValue::F64(scaled_value),
+    }
+}
+
+/// Encodes a value in `length` registers (one register is a 16 bits integer,
+/// otherwise a 32 bits integer or float) with the given swap.
+pub fn to_registers(value: f64, swap: &Swap, data_type: &Type, length: u16) -> Vec<u16> {
+    let data = match (data_type, length) {
+        (Type::Integer, 1) => vec![value.round() as i32 as u16],
+        (Type::Integer, _) => {
+            let num = value.round() as i64 as u32;
+            vec![(num >> 16) as u16, num as u16]
+        }
+        (Type::Float, _) => {
+            let num = (value as f32).to_bits();
+            vec![(num >> 16) as u16, num as u16]
+        }
+    };
+    apply_swap(data, swap)
+}
+
+// Every swap is its own inverse, so the same function converts from and to the
+// device byte order.
+fn apply_swap(data: Vec<u16>, swap: &Swap) -> Vec<u16> {
+    match swap {
+        Swap::LittleEndian => data.iter().map(swap_bytes).rev().collect(),
+        Swap::BigEndian => data,
+        Swap::LittleEndianSwap => swap_words(data.iter().map(swap_bytes).rev().collect()),
+        Swap::BigEndianSwap => swap_words(data),
     }
 }
 
@@ -149,10 +476,13 @@ fn is_integer(value: f32) -> bool {
     value == value.round()
 }
 
+// A single register has no words to swap.
 fn swap_words(words: Vec<u16>) -> Vec<u16> {
     let mut data = words;
-    data.swap(0, 1);
-    data.to_vec()
+    if data.len() == 2 {
+        data.swap(0, 1);
+    }
+    data
 }
 
 fn swap_bytes(word: &u16) -> u16 {
@@ -187,21 +517,371 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_to_device_error() {
+        use super::to_device_error;
+        use crate::models::device::{DeviceError, ModbusException};
+        use std::io::{Error, ErrorKind};
+
+        let exception = to_device_error(Error::other("Modbus function 3: Illegal data address"));
+        assert_eq!(
+            DeviceError::Exception(ModbusException::IllegalDataAddress),
+            exception
+        );
+        assert_eq!(
+            r#"{"code":"modbus_exception","exception_code":2,"message":"Illegal data address (0x02)."}"#,
+            serde_json::to_string(&exception).unwrap()
+        );
+
+        let refused = to_device_error(Error::new(ErrorKind::ConnectionRefused, "refused"));
+        assert_eq!(DeviceError::Transport("refused".to_string()), refused);
+        assert_eq!(
+            r#"{"code":"transport","message":"refused"}"#,
+            serde_json::to_string(&refused).unwrap()
+        );
+    }
+
+    // Every exception answered by a device is recognized from the error of the
+    // tokio-modbus client.
+    #[tokio::test]
+    async fn test_client_exceptions() {
+        use super::to_device_error;
+        use crate::models::device::{DeviceError, ModbusException};
+        use tokio_modbus::prelude::*;
+
+        #[derive(Clone)]
+        struct Failing(u8);
+
+        impl tokio_modbus::server::Service for Failing {
+            type Request = Request;
+            type Response = Response;
+            type Error = std::io::Error;
+            type Future = futures::future::Ready<Result<Response, std::io::Error>>;
+
+            fn call(&self, _: Self::Request) -> Self::Future {
+                futures::future::ready(Ok(Response::Custom(0x83, vec![self.0])))
+            }
+        }
+
+        for exception in ModbusException::ALL {
+            let address = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let service = Failing(exception.code());
+            tokio::spawn(async move {
+                tokio_modbus::server::tcp::Server::new(address)
+                    .serve(move || Ok(service.to_owned()))
+                    .await
+            });
+            let mut ctx = loop {
+                if let Ok(ctx) = tcp::connect(address).await {
+                    break ctx;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            };
+            let err = ctx.read_holding_registers(0, 1).await.unwrap_err();
+            assert_eq!(DeviceError::Exception(exception), to_device_error(err));
+        }
+
+        // The same text in another error is not an exception.
+        let text = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Modbus function 3: Illegal data address",
+        );
+        assert!(matches!(to_device_error(text), DeviceError::Transport(_)));
+    }
+
+    #[test]
+    fn test_encode_write() {
+        use super::{encode_write, parse_value, Command, DeviceError, Swap, TagValue, Type};
+
+        let holding = |text: &str, data_type: Type, length: u16, multiplier: f32| {
+            let value = parse_value(text, &Command::Holding, length)?;
+            encode_write(
+                &value,
+                &Command::Holding,
+                &Swap::BigEndian,
+                &data_type,
+                length,
+                multiplier,
+            )
+        };
+        let bits = 21.5f32.to_bits();
+        assert_eq!(
+            Ok(vec![(bits >> 16) as u16, bits as u16]),
+            holding("21.5", Type::Float, 2, 1.0)
+        );
+        assert_eq!(Ok(vec![0, 215]), holding("21.5", Type::Integer, 2, 0.1));
+        assert_eq!(
+            Ok(vec![0xFFFF, 0xFFFE]),
+            holding("-2", Type::Integer, 2, 1.0)
+        );
+        assert_eq!(Ok(vec![0xFFFF]), holding("65535", Type::Integer, 1, 1.0));
+        assert_eq!(Ok(vec![0xFFFF]), holding("-1", Type::Integer, 1, 1.0));
+        assert_eq!(
+            Ok(vec![0x0100, 0]),
+            encode_write(
+                &TagValue::I32(1),
+                &Command::Holding,
+                &Swap::LittleEndian,
+                &Type::Integer,
+                2,
+                1.0
+            )
+        );
+
+        let code = |result: Result<Vec<u16>, DeviceError>| result.unwrap_err().code();
+        assert_eq!(
+            "invalid_command",
+            code(holding("65536", Type::Integer, 1, 1.0))
+        );
+        assert_eq!(
+            "invalid_command",
+            code(holding("-32769", Type::Integer, 1, 1.0))
+        );
+        assert_eq!(
+            "invalid_command",
+            code(holding("5000000000", Type::Integer, 2, 1.0))
+        );
+        assert_eq!("invalid_command", code(holding("NaN", Type::Float, 2, 1.0)));
+        assert_eq!(
+            "invalid_command",
+            code(holding("1e39", Type::Float, 2, 1.0))
+        );
+        assert_eq!("invalid_command", code(holding("abc", Type::Float, 2, 1.0)));
+        assert_eq!("config", code(holding("1", Type::Float, 1, 1.0)));
+        assert_eq!("config", code(holding("1", Type::Integer, 4, 1.0)));
+
+        let coil = |text: &str| {
+            let value = parse_value(text, &Command::Coil, 1)?;
+            encode_write(
+                &value,
+                &Command::Coil,
+                &Swap::BigEndian,
+                &Type::Integer,
+                1,
+                1.0,
+            )
+        };
+        assert_eq!(Ok(vec![1]), coil("ON"));
+        assert_eq!(Ok(vec![1]), coil("true"));
+        assert_eq!(Ok(vec![0]), coil("off"));
+        assert_eq!(Ok(vec![0]), coil("0"));
+        assert_eq!("invalid_command", code(coil("2")));
+        assert_eq!(
+            "invalid_command",
+            code(encode_write(
+                &TagValue::I32(2),
+                &Command::Coil,
+                &Swap::BigEndian,
+                &Type::Integer,
+                1,
+                1.0
+            ))
+        );
+    }
+
+    #[test]
+    fn test_check_read_back() {
+        use super::{check_read_back, Command, DeviceError, Swap, TagValue, Type};
+
+        let holding = |written: &[u16], read_back: &[u16]| {
+            check_read_back(
+                written,
+                read_back,
+                &Command::Holding,
+                &Swap::BigEndian,
+                &Type::Integer,
+                &0.1,
+                0xFFFF,
+            )
+        };
+        assert_eq!(Ok(()), holding(&[0, 215], &[0, 215]));
+        let mismatch = holding(&[0, 215], &[0, 100]).unwrap_err();
+        assert_eq!(
+            DeviceError::WriteMismatch(TagValue::F32(21.5), TagValue::I32(10)),
+            mismatch
+        );
+        assert_eq!(
+            r#"{"code":"write_mismatch","actual_value":{"I32":10},"message":"The device holds 10 instead of 21.5."}"#,
+            serde_json::to_string(&mismatch).unwrap()
+        );
+
+        let coil = check_read_back(
+            &[1],
+            &[0],
+            &Command::Coil,
+            &Swap::BigEndian,
+            &Type::Integer,
+            &1.0,
+            0xFFFF,
+        );
+        assert_eq!(
+            Err(DeviceError::WriteMismatch(
+                TagValue::I32(1),
+                TagValue::I32(0)
+            )),
+            coil
+        );
+    }
+
+    #[test]
+    fn test_write_functions() {
+        use super::{
+            check_read_back, decode, encode_write, parse_value, write_function, Command, Swap,
+            TagValue, Type, WriteFunction,
+        };
+
+        let function = |function: WriteFunction, command: Command, words: usize| {
+            write_function(&function, &command, words).map_err(|err| err.code())
+        };
+        assert_eq!(
+            Ok(WriteFunction::FC05),
+            function(WriteFunction::Auto, Command::Coil, 1)
+        );
+        assert_eq!(
+            Ok(WriteFunction::FC15),
+            function(WriteFunction::Auto, Command::Coil, 3)
+        );
+        assert_eq!(
+            Ok(WriteFunction::FC16),
+            function(WriteFunction::Auto, Command::Holding, 1)
+        );
+        assert_eq!(
+            Ok(WriteFunction::FC06),
+            function(WriteFunction::FC06, Command::Holding, 1)
+        );
+        assert_eq!(
+            Ok(WriteFunction::FC23),
+            function(WriteFunction::FC23, Command::Holding, 2)
+        );
+        assert_eq!(
+            Err("config"),
+            function(WriteFunction::FC06, Command::Holding, 2)
+        );
+        assert_eq!(
+            Err("config"),
+            function(WriteFunction::FC22, Command::Holding, 2)
+        );
+        assert_eq!(
+            Err("config"),
+            function(WriteFunction::FC05, Command::Holding, 1)
+        );
+        assert_eq!(
+            Err("config"),
+            function(WriteFunction::FC16, Command::Coil, 1)
+        );
+        assert_eq!(
+            Err("config"),
+            function(WriteFunction::FC16, Command::Input, 1)
+        );
+
+        let coils = |text: &str| {
+            let value = parse_value(text, &Command::Coil, 3)?;
+            encode_write(
+                &value,
+                &Command::Coil,
+                &Swap::BigEndian,
+                &Type::Integer,
+                3,
+                1.0,
+            )
+        };
+        assert_eq!(Ok(vec![1, 0, 1]), coils("[true, off, 1]"));
+        assert_eq!(Ok(vec![0, 1, 1]), coils("6"));
+        assert_eq!("invalid_command", coils("[1, 0]").unwrap_err().code());
+        assert_eq!("invalid_command", coils("8").unwrap_err().code());
+        assert_eq!("invalid_command", coils("[1, 0, 2]").unwrap_err().code());
+        assert_eq!(
+            Ok(TagValue::I32(5)),
+            decode(
+                vec![1, 0, 1],
+                &Command::Coil,
+                &Swap::BigEndian,
+                &Type::Integer,
+                &1.0
+            )
+        );
+
+        // Only the bits of the mask are compared after a mask write.
+        let masked = |read_back: u16| {
+            check_read_back(
+                &[0x00AB],
+                &[read_back],
+                &Command::Holding,
+                &Swap::BigEndian,
+                &Type::Integer,
+                &1.0,
+                0x00FF,
+            )
+        };
+        assert_eq!(Ok(()), masked(0x12AB));
+        assert!(masked(0x12AC).is_err());
+    }
+
+    #[test]
+    fn test_parse_counter() {
+        use super::{parse_readed, Swap, TagValue, Type};
+        use crate::models::counter::{Counter, CounterOutput, Counters};
+        use crate::models::tag::TagResponse;
+        use chrono::{DateTime, Duration, Utc};
+
+        // 2^24 + 1 pulses, that an f32 rounds to 2^24.
+        let read = |data: Vec<u16>, multiplier: f32| TagResponse {
+            id: "dev/Energy".to_string(),
+            value: parse_readed(data, &Swap::BigEndian, &Type::Integer, &multiplier).unwrap(),
+            cached: false,
+            timestamp: None,
+        };
+        assert_eq!(
+            TagValue::I32(16_777_217),
+            read(vec![0x0100, 0x0001], 1.0).value
+        );
+        assert_eq!(
+            TagValue::F64(16_777.217),
+            read(vec![0x0100, 0x0001], 0.001).value
+        );
+
+        let start = DateTime::<Utc>::from_timestamp(1_700_000_000, 0).unwrap();
+        let delta = Counter {
+            bits: 32,
+            multiplier: 0.001,
+            output: CounterOutput::Delta,
+        };
+        let mut counters = Counters::default();
+        counters.apply(&delta, read(vec![0x0100, 0x0001], 0.001), start);
+        let response = counters.apply(
+            &delta,
+            read(vec![0x0100, 0x0002], 0.001),
+            start + Duration::seconds(1),
+        );
+        assert!((response.value.to_f64() - 0.001).abs() < 1e-9);
+
+        // A 32 bits counter over 2^31 is decoded as a negative integer.
+        let total = Counter {
+            output: CounterOutput::Total,
+            ..delta
+        };
+        let response = counters.apply(&total, read(vec![0x8000, 0x0000], 0.001), start);
+        assert_eq!(TagValue::F64(2_147_483.648), response.value);
+    }
+
     #[test]
     fn test_parse_readed() {
         use super::{parse_readed, Swap, TagValue, Type};
 
         let u16_vec: Vec<u16> = vec![0, 0xE8];
         assert_eq!(
-            TagValue::F32(23.2),
+            Ok(TagValue::F32(23.2)),
             parse_readed(u16_vec.to_owned(), &Swap::BigEndian, &Type::Integer, &0.1)
         );
         assert_eq!(
-            TagValue::I32(232),
+            Ok(TagValue::I32(232)),
             parse_readed(u16_vec.to_owned(), &Swap::BigEndian, &Type::Integer, &1.0)
         );
         assert_eq!(
-            TagValue::I32(15204352),
+            Ok(TagValue::I32(15204352)),
             parse_readed(
                 u16_vec.to_owned(),
                 &Swap::BigEndianSwap,
@@ -210,7 +890,7 @@ mod tests {
             )
         );
         assert_eq!(
-            TagValue::I32(15204352),
+            Ok(TagValue::I32(15204352)),
             parse_readed(
                 u16_vec.to_owned(),
                 &Swap::BigEndianSwap,
@@ -219,7 +899,7 @@ mod tests {
             )
         );
         assert_eq!(
-            TagValue::I32(-402653184),
+            Ok(TagValue::I32(-402653184)),
             parse_readed(
                 u16_vec.to_owned(),
                 &Swap::LittleEndian,
@@ -228,7 +908,7 @@ mod tests {
             )
         );
         assert_eq!(
-            TagValue::I32(59392),
+            Ok(TagValue::I32(59392)),
             parse_readed(
                 u16_vec.to_owned(),
                 &Swap::LittleEndianSwap,
@@ -237,4 +917,22 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn test_swap_one_register() {
+        use super::{parse_readed, to_registers, Swap, TagValue, Type};
+
+        for swap in [Swap::BigEndianSwap, Swap::LittleEndianSwap] {
+            let words = to_registers(1234.0, &swap, &Type::Integer, 1);
+            assert_eq!(1, words.len());
+            assert_eq!(
+                Ok(TagValue::I32(1234)),
+                parse_readed(words, &swap, &Type::Integer, &1.0)
+            );
+        }
+        assert_eq!(
+            vec![0xD204],
+            to_registers(1234.0, &Swap::LittleEndianSwap, &Type::Integer, 1)
+        );
+    }
 }
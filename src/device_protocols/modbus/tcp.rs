@@ -1,4 +1,10 @@
 use super::shared;
+use crate::device_protocols::bus::Bus;
+use crate::device_protocols::health::Breaker;
+use crate::device_protocols::safety::List;
+use crate::models::aggregation::Aggregate;
+use crate::models::counter::CounterOutput;
+use crate::models::deadband::DeadbandType;
 use crate::{gen_readable_struct, DeviceProtocols};
 use tokio_modbus::{client::Context, prelude::*};
 
@@ -9,6 +15,16 @@ gen_readable_struct!(
         port: u16,
         slave: u8,
         read_freq: device::ReadFrequency,
+        profile: String = String::new(),
+        max_concurrent_requests: usize = 4,
+        inter_request_delay_ms: u64 = 0,
+        timeout_ms: u64 = 4000,
+        retries: u32 = 0,
+        backoff_ms: u64 = 500,
+        backoff_max_ms: u64 = 5000,
+        breaker_failures: u32 = 3,
+        breaker_probe_s: u64 = 60,
+        cache_max_age_ms: u64 = 0,
     }
 );
 
@@ -22,13 +38,80 @@ gen_readable_struct!(
         data_type: shared::Type,
         mode: super::super::Mode,
         multiplier: f32,
+        min: f32 = f32::MIN,
+        max: f32 = f32::MAX,
+        allowed_values: List<f32> = List::default(),
+        select_before_operate_s: u64 = 0,
+        verify_write: bool = false,
+        verify_delay_ms: u64 = 100,
+        write_function: shared::WriteFunction = shared::WriteFunction::Auto,
+        write_mask: u16 = 65535,
+        aggregate_window_s: u64 = 0,
+        aggregate: List<Aggregate> = List::default(),
+        publish_on_change: bool = false,
+        deadband: f32 = 0.0,
+        deadband_type: DeadbandType = DeadbandType::Absolute,
+        heartbeat_min: u64 = 0,
+        counter_bits: u32 = 0,
+        counter_output: CounterOutput = CounterOutput::Total,
     }
 );
 
+// Built from ini sections, so the tests get the same defaults as the files.
+#[cfg(test)]
+fn from_section<T>(section: &[(&str, &str)]) -> T
+where
+    T: TryFrom<std::collections::HashMap<String, String>>,
+    T::Error: std::fmt::Debug,
+{
+    let section = section.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+    T::try_from(section.collect()).unwrap()
+}
+
+#[cfg(test)]
+impl Connection {
+    /// Connection of the tests, polled every second with short timeouts and
+    /// without backoff.
+    pub fn for_test(name: &str, address: SocketAddr) -> Self {
+        from_section(&[
+            ("name", name),
+            ("ip", &address.ip().to_string()),
+            ("port", &address.port().to_string()),
+            ("slave", "1"),
+            ("read_freq", "1 s"),
+            ("max_concurrent_requests", "1"),
+            ("timeout_ms", "1000"),
+            ("backoff_ms", "0"),
+            ("backoff_max_ms", "0"),
+            ("breaker_probe_s", "1"),
+        ])
+    }
+}
+
+#[cfg(test)]
+impl Tag {
+    /// Read tag of the tests, a 32 bits integer in the holding registers 0 and 1.
+    pub fn for_test(name: &str) -> Self {
+        from_section(&[
+            ("name", name),
+            ("address", "0"),
+            ("length", "2"),
+            ("command", "Holding"),
+            ("swap", "BigEndian"),
+            ("data_type", "Integer"),
+            ("mode", "Read"),
+            ("multiplier", "1"),
+            ("verify_delay_ms", "0"),
+        ])
+    }
+}
+
 use crate::config_files::ini_parser;
+use std::sync::Arc;
+use std::time::Duration;
 pub fn reader<F>(constructor: F, path: &str) -> Vec<DeviceProtocols>
 where
-    F: Fn(Connection, Tag) -> DeviceProtocols,
+    F: Fn(Arc<Bus>, Arc<Breaker>, Connection, Tag) -> DeviceProtocols,
 {
     let path = path.to_string();
 
@@ -36,22 +119,38 @@ where
         .into_iter()
         .next()
         .unwrap();
+    let bus = Bus::for_endpoint(
+        &format!("{}:{}", connection.ip, connection.port),
+        connection.max_concurrent_requests,
+        Duration::from_millis(connection.inter_request_delay_ms),
+    );
+    let breaker = Arc::new(Breaker::new(
+        connection.breaker_failures,
+        Duration::from_secs(connection.breaker_probe_s),
+    ));
 
-    let tags = ini_parser::read_file::<Tag>(&(format!("{}/publishers.ini", &path)));
+    let tags = ini_parser::read_file_with_profile::<Tag>(
+        &connection.profile,
+        &(format!("{}/publishers.ini", &path)),
+    );
     tags.iter()
-        .map(|tag| constructor(connection.to_owned(), tag.to_owned()))
+        .map(|tag| {
+            constructor(
+                bus.to_owned(),
+                breaker.to_owned(),
+                connection.to_owned(),
+                tag.to_owned(),
+            )
+        })
         .collect()
 }
 
 use crate::models::device;
-use crate::models::device::{ReadError, WriteError};
+use crate::models::device::DeviceError;
 use crate::models::tag::{TagResponse, TagValue};
 
-#[derive(Debug, Clone)]
-struct Error(String);
-
 use std::net::SocketAddr;
-async fn connect(con: &Connection) -> Result<Context, Error> {
+async fn connect(con: &Connection) -> Result<Context, DeviceError> {
     let Connection {
         ip, port, slave, ..
     } = con.to_owned();
@@ -59,27 +158,66 @@ async fn connect(con: &Connection) -> Result<Context, Error> {
 
     match client::tcp::connect_slave(socket_address, Slave(slave)).await {
         Ok(ctx) => Ok(ctx),
-        Err(err) => Err(Error(err.to_string())),
+        Err(err) => Err(DeviceError::Transport(err.to_string())),
     }
 }
 
-pub async fn read(con: &Connection, tag: &Tag) -> Result<TagResponse, ReadError> {
-    let mut ctx = connect(con).await.map_err(|err| ReadError(err.0))?;
+pub async fn read(con: &Connection, tag: &Tag) -> Result<TagResponse, DeviceError> {
+    let mut ctx = connect(con).await?;
 
     let raw_data = shared::read(&mut ctx, &tag.command, tag.address, tag.length).await?;
-    let parsed_data = shared::parse_readed(raw_data, &tag.swap, &tag.data_type, &tag.multiplier);
+    let parsed_data = shared::decode(
+        raw_data,
+        &tag.command,
+        &tag.swap,
+        &tag.data_type,
+        &tag.multiplier,
+    )?;
 
     Ok(TagResponse {
         id: format!("{}/{}", con.name, tag.name),
         value: parsed_data,
+        cached: false,
+        timestamp: None,
     })
 }
 
-pub async fn write(con: &Connection, tag: &Tag, value: TagValue) -> Result<(), WriteError> {
-    let mut ctx = connect(con).await.map_err(|err| WriteError(err.0))?;
+pub async fn write(con: &Connection, tag: &Tag, value: TagValue) -> Result<(), DeviceError> {
+    let value_to_write = shared::encode_write(
+        &value,
+        &tag.command,
+        &tag.swap,
+        &tag.data_type,
+        tag.length,
+        tag.multiplier,
+    )?;
+    let function = shared::write_function(&tag.write_function, &tag.command, value_to_write.len())?;
+
+    let mut ctx = connect(con).await?;
+    shared::write(
+        &mut ctx,
+        &function,
+        tag.address,
+        &value_to_write,
+        tag.write_mask,
+    )
+    .await?;
 
-    let value_to_write = shared::parse_write(&value, &tag.swap);
-    shared::write(&mut ctx, &tag.command, tag.address, &value_to_write).await?;
+    if tag.verify_write {
+        tokio::time::sleep(Duration::from_millis(tag.verify_delay_ms)).await;
+        let mut ctx = connect(con).await?;
+        let length = value_to_write.len() as u16;
+        let read_back = shared::read(&mut ctx, &tag.command, tag.address, length).await?;
+        shared::check_read_back(
+            &value_to_write,
+            &read_back,
+            &tag.command,
+            &tag.swap,
+            &tag.data_type,
+            &tag.multiplier,
+            tag.write_mask,
+        )?;
+    }
 
     Ok(())
 }
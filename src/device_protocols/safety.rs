@@ -0,0 +1,277 @@
+use crate::config_files::ini_parser;
+use crate::gen_readable_struct;
+use crate::models::device::DeviceError;
+use crate::models::tag::TagValue;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+pub const WRITE_POLICY_FILE: &str = "gateway.ini";
+
+/// Comma separated list of values in an ini field, i.e. `0, 10, 20`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct List<T>(pub Vec<T>);
+
+// Derived it would need `T: Default`.
+impl<T> Default for List<T> {
+    fn default() -> Self {
+        List(Vec::new())
+    }
+}
+
+impl<T: FromStr> FromStr for List<T> {
+    type Err = T::Err;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<T>, _>>()
+            .map(List)
+    }
+}
+
+gen_readable_struct!(
+    struct WritePolicy {
+        read_only: bool = false,
+        writable_tags: List<String> = List::default(),
+    }
+);
+
+// Policy of the whole gateway, taken from the optional gateway.ini file.
+static POLICY: OnceLock<WritePolicy> = OnceLock::new();
+
+// Tags selected for operation, with the value armed and when it expires.
+static SELECTIONS: LazyLock<Mutex<HashMap<String, (TagValue, Instant)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Write constraints of a tag.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub min: f32,
+    pub max: f32,
+    pub allowed_values: Vec<f32>,
+    pub select_before_operate: Option<Duration>,
+}
+
+/// Reads the optional gateway.ini file. It is called at startup, so an invalid
+/// file stops the gateway instead of the first write.
+pub fn load_policy() {
+    let policy = match std::path::Path::new(WRITE_POLICY_FILE).exists() {
+        true => ini_parser::read_file::<WritePolicy>(WRITE_POLICY_FILE)
+            .into_iter()
+            .next()
+            .expect("Invalid gateway.ini file"),
+        false => unrestricted(),
+    };
+    if POLICY.set(policy).is_err() {
+        tracing::warn!("The write policy was already loaded");
+    }
+}
+
+fn unrestricted() -> WritePolicy {
+    WritePolicy {
+        read_only: false,
+        writable_tags: List::default(),
+    }
+}
+
+/// Policy loaded at startup, without restrictions if it was not loaded.
+pub fn policy() -> &'static WritePolicy {
+    POLICY.get_or_init(unrestricted)
+}
+
+/// Checks that the value can be written on the tag with the id `device/tag`.
+pub fn check_write(
+    policy: &WritePolicy,
+    id: &str,
+    value: &TagValue,
+    limits: &Limits,
+) -> Result<(), DeviceError> {
+    if policy.read_only {
+        return Err(DeviceError::Forbidden(
+            "The gateway is read only.".to_string(),
+        ));
+    }
+    let writable_tags = &policy.writable_tags.0;
+    if !writable_tags.is_empty() && !writable_tags.iter().any(|tag| tag == id) {
+        return Err(DeviceError::Forbidden(format!(
+            "The tag {} cannot be written remotely.",
+            id
+        )));
+    }
+
+    let number = value.to_f32();
+    if number < limits.min || number > limits.max {
+        return Err(DeviceError::InvalidCommand(format!(
+            "The value {} is out of the limits [{}, {}] of the tag {}.",
+            value, limits.min, limits.max, id
+        )));
+    }
+    if !limits.allowed_values.is_empty() && !limits.allowed_values.contains(&number) {
+        return Err(DeviceError::InvalidCommand(format!(
+            "The value {} is not allowed for the tag {}.",
+            value, id
+        )));
+    }
+    Ok(())
+}
+
+// The selections abandoned without an operation are removed when they expire.
+fn remove_expired(selections: &mut HashMap<String, (TagValue, Instant)>) {
+    let now = Instant::now();
+    selections.retain(|_, (_, expiration)| *expiration >= now);
+}
+
+/// Arms the value for the tag, it must be operated before `window` elapses.
+pub fn select(id: &str, value: TagValue, window: Duration) {
+    let mut selections = SELECTIONS.lock().unwrap();
+    remove_expired(&mut selections);
+    selections.insert(id.to_string(), (value, Instant::now() + window));
+}
+
+/// Consumes the selection of the tag, that must be armed with the same value.
+pub fn operate(id: &str, value: &TagValue) -> Result<(), DeviceError> {
+    let selection = {
+        let mut selections = SELECTIONS.lock().unwrap();
+        // Taken before the expired ones are removed, to tell it has expired.
+        let selection = selections.remove(id);
+        remove_expired(&mut selections);
+        selection
+    };
+    match selection {
+        None => Err(DeviceError::Forbidden(format!(
+            "The tag {} must be selected before operating.",
+            id
+        ))),
+        Some((_, expiration)) if Instant::now() > expiration => Err(DeviceError::Forbidden(
+            format!("The selection of the tag {} has expired.", id),
+        )),
+        Some((selected, _)) if selected != *value => Err(DeviceError::Forbidden(format!(
+            "The value {} does not match the selected value {}.",
+            value, selected
+        ))),
+        Some(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_write, operate, select, Limits, List, WritePolicy, SELECTIONS};
+    use crate::models::tag::TagValue;
+    use std::time::Duration;
+
+    #[test]
+    fn test_check_write() {
+        let code = |result: Result<(), crate::models::device::DeviceError>| {
+            result.map_err(|err| err.code())
+        };
+        let open = WritePolicy {
+            read_only: false,
+            writable_tags: List::default(),
+        };
+        let limits = Limits {
+            min: 0.0,
+            max: 100.0,
+            allowed_values: Vec::new(),
+            select_before_operate: None,
+        };
+        assert_eq!(
+            Ok(()),
+            check_write(&open, "dev/Sp", &TagValue::F32(21.5), &limits)
+        );
+        assert_eq!(
+            Err("invalid_command"),
+            code(check_write(&open, "dev/Sp", &TagValue::I32(101), &limits))
+        );
+        assert_eq!(
+            Err("invalid_command"),
+            code(check_write(&open, "dev/Sp", &TagValue::F32(-0.5), &limits))
+        );
+
+        let allowed = Limits {
+            allowed_values: "0, 10,20".parse::<List<f32>>().unwrap().0,
+            ..limits.to_owned()
+        };
+        assert_eq!(
+            Ok(()),
+            check_write(&open, "dev/Sp", &TagValue::I32(10), &allowed)
+        );
+        assert_eq!(
+            Err("invalid_command"),
+            code(check_write(&open, "dev/Sp", &TagValue::I32(15), &allowed))
+        );
+
+        let allowlist = WritePolicy {
+            read_only: false,
+            writable_tags: "dev/Sp".parse().unwrap(),
+        };
+        assert_eq!(
+            Ok(()),
+            check_write(&allowlist, "dev/Sp", &TagValue::I32(1), &limits)
+        );
+        assert_eq!(
+            Err("forbidden"),
+            code(check_write(
+                &allowlist,
+                "dev/Pump",
+                &TagValue::I32(1),
+                &limits
+            ))
+        );
+
+        let read_only = WritePolicy {
+            read_only: true,
+            ..open
+        };
+        assert_eq!(
+            Err("forbidden"),
+            code(check_write(
+                &read_only,
+                "dev/Sp",
+                &TagValue::I32(1),
+                &limits
+            ))
+        );
+    }
+
+    #[test]
+    fn test_select_before_operate() {
+        let code = |result: Result<(), crate::models::device::DeviceError>| {
+            result.map_err(|err| err.code())
+        };
+        assert_eq!(
+            Err("forbidden"),
+            code(operate("sbo/Breaker", &TagValue::I32(1)))
+        );
+
+        select("sbo/Breaker", TagValue::I32(1), Duration::from_secs(5));
+        assert_eq!(
+            Err("forbidden"),
+            code(operate("sbo/Breaker", &TagValue::I32(0)))
+        );
+        // A failed operation consumes the selection.
+        assert_eq!(
+            Err("forbidden"),
+            code(operate("sbo/Breaker", &TagValue::I32(1)))
+        );
+
+        select("sbo/Breaker", TagValue::I32(1), Duration::from_secs(5));
+        assert_eq!(Ok(()), operate("sbo/Breaker", &TagValue::I32(1)));
+
+        select("sbo/Breaker", TagValue::I32(1), Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(
+            Err("forbidden"),
+            code(operate("sbo/Breaker", &TagValue::I32(1)))
+        );
+
+        // An abandoned selection is removed once expired.
+        select("sbo/Abandoned", TagValue::I32(1), Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(5));
+        select("sbo/Breaker", TagValue::I32(1), Duration::from_secs(5));
+        assert!(!SELECTIONS.lock().unwrap().contains_key("sbo/Abandoned"));
+    }
+}
@@ -0,0 +1,426 @@
+use std::str::FromStr;
+
+/// Binary operators of the expressions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+// Operators of each precedence level, from the lowest to the highest.
+const LEVELS: [&[(&str, Operator)]; 5] = [
+    &[("||", Operator::Or)],
+    &[("&&", Operator::And)],
+    &[
+        ("==", Operator::Eq),
+        ("!=", Operator::Ne),
+        ("<=", Operator::Le),
+        (">=", Operator::Ge),
+        ("<", Operator::Lt),
+        (">", Operator::Gt),
+    ],
+    &[("+", Operator::Add), ("-", Operator::Sub)],
+    &[
+        ("*", Operator::Mul),
+        ("/", Operator::Div),
+        ("%", Operator::Rem),
+    ],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    Abs,
+    Min,
+    Max,
+    Sqrt,
+    If,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "abs" => Some(Function::Abs),
+            "min" => Some(Function::Min),
+            "max" => Some(Function::Max),
+            "sqrt" => Some(Function::Sqrt),
+            "if" => Some(Function::If),
+            _ => None,
+        }
+    }
+
+    fn accepts(&self, arguments: usize) -> bool {
+        match self {
+            Function::Abs | Function::Sqrt => arguments == 1,
+            Function::Min | Function::Max => arguments >= 1,
+            Function::If => arguments == 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Number(f64),
+    Tag(String),
+    Negate(Box<Node>),
+    Not(Box<Node>),
+    Binary(Operator, Box<Node>, Box<Node>),
+    Call(Function, Vec<Node>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Tag(String),
+    Name(String),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 17] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "%", "!", "(", ")", ",",
+];
+
+fn is_word(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '.')
+}
+
+fn word_end(text: &str) -> usize {
+    text.find(|c| !is_word(c)).unwrap_or(text.len())
+}
+
+// Two words that are not numbers joined by a slash are a tag (`device/tag`),
+// otherwise the slash is a division. A word that can be parsed as a number is
+// a number and the rest are function names. A tag whose name has other
+// characters is written between brackets (`[device 1/tag-a]`).
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c == '[' {
+            let end = rest
+                .find(']')
+                .ok_or(format!("Unclosed tag name in {}.", text))?;
+            tokens.push(Token::Tag(rest[1..end].trim().to_string()));
+            rest = &rest[end + 1..];
+        } else if is_word(c) {
+            let end = word_end(rest);
+            let word = &rest[..end];
+            let tag = rest[end..]
+                .strip_prefix('/')
+                .map(|after| &after[..word_end(after)])
+                .filter(|tag| !tag.is_empty() && tag.parse::<f64>().is_err());
+            match (word.parse::<f64>(), tag) {
+                (Err(_), Some(tag)) => {
+                    tokens.push(Token::Tag(format!("{}/{}", word, tag)));
+                    rest = &rest[end + 1 + tag.len()..];
+                }
+                (Ok(number), _) => {
+                    tokens.push(Token::Number(number));
+                    rest = &rest[end..];
+                }
+                (Err(_), None) => {
+                    tokens.push(Token::Name(word.to_string()));
+                    rest = &rest[end..];
+                }
+            }
+        } else {
+            let symbol = SYMBOLS
+                .into_iter()
+                .find(|symbol| rest.starts_with(symbol))
+                .ok_or(format!("Unexpected character {} in {}.", c, text))?;
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: &'static str) -> bool {
+        let found = self.peek() == Some(&Token::Symbol(symbol));
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), String> {
+        match self.eat(symbol) {
+            true => Ok(()),
+            false => Err(format!("Expected {} instead of {:?}.", symbol, self.peek())),
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Node, String> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut node = self.binary(level + 1)?;
+        'operators: loop {
+            for (symbol, operator) in LEVELS[level] {
+                if self.eat(symbol) {
+                    let right = self.binary(level + 1)?;
+                    node = Node::Binary(*operator, Box::new(node), Box::new(right));
+                    continue 'operators;
+                }
+            }
+            return Ok(node);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.eat("-") {
+            return Ok(Node::Negate(Box::new(self.unary()?)));
+        }
+        if self.eat("!") {
+            return Ok(Node::Not(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Node::Number(number)),
+            Some(Token::Tag(id)) => Ok(Node::Tag(id)),
+            Some(Token::Name(name)) => {
+                let function =
+                    Function::from_name(&name).ok_or(format!("Unknown function {}.", name))?;
+                self.expect("(")?;
+                let mut arguments = vec![self.binary(0)?];
+                while self.eat(",") {
+                    arguments.push(self.binary(0)?);
+                }
+                self.expect(")")?;
+                if !function.accepts(arguments.len()) {
+                    return Err(format!(
+                        "Wrong number of arguments ({}) for {}.",
+                        arguments.len(),
+                        name
+                    ));
+                }
+                Ok(Node::Call(function, arguments))
+            }
+            Some(Token::Symbol("(")) => {
+                let node = self.binary(0)?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            token => Err(format!("Unexpected {:?}.", token)),
+        }
+    }
+}
+
+fn truth(condition: bool) -> f64 {
+    match condition {
+        true => 1.0,
+        false => 0.0,
+    }
+}
+
+impl Node {
+    fn evaluate(&self, value_of: &dyn Fn(&str) -> Option<f64>) -> Result<f64, String> {
+        let evaluate = |node: &Node| node.evaluate(value_of);
+        Ok(match self {
+            Node::Number(number) => *number,
+            Node::Tag(id) => {
+                value_of(id).ok_or(format!("The value of {} is not available.", id))?
+            }
+            Node::Negate(node) => -evaluate(node)?,
+            Node::Not(node) => truth(evaluate(node)? == 0.0),
+            // The right side of the logical operators is only evaluated when needed.
+            Node::Binary(Operator::And, left, right) => match evaluate(left)? != 0.0 {
+                true => truth(evaluate(right)? != 0.0),
+                false => 0.0,
+            },
+            Node::Binary(Operator::Or, left, right) => match evaluate(left)? != 0.0 {
+                true => 1.0,
+                false => truth(evaluate(right)? != 0.0),
+            },
+            Node::Binary(operator, left, right) => {
+                let (left, right) = (evaluate(left)?, evaluate(right)?);
+                match operator {
+                    Operator::Eq => truth(left == right),
+                    Operator::Ne => truth(left != right),
+                    Operator::Lt => truth(left < right),
+                    Operator::Le => truth(left <= right),
+                    Operator::Gt => truth(left > right),
+                    Operator::Ge => truth(left >= right),
+                    Operator::Add => left + right,
+                    Operator::Sub => left - right,
+                    Operator::Mul => left * right,
+                    Operator::Div => left / right,
+                    Operator::Rem => left % right,
+                    Operator::And | Operator::Or => unreachable!(),
+                }
+            }
+            // Only the chosen branch of an if is evaluated.
+            Node::Call(Function::If, arguments) => match evaluate(&arguments[0])? != 0.0 {
+                true => evaluate(&arguments[1])?,
+                false => evaluate(&arguments[2])?,
+            },
+            Node::Call(function, arguments) => {
+                let values = arguments
+                    .iter()
+                    .map(evaluate)
+                    .collect::<Result<Vec<f64>, String>>()?;
+                match function {
+                    Function::Abs => values[0].abs(),
+                    Function::Sqrt => values[0].sqrt(),
+                    Function::Min => values.into_iter().fold(f64::INFINITY, f64::min),
+                    Function::Max => values.into_iter().fold(f64::NEG_INFINITY, f64::max),
+                    Function::If => unreachable!(),
+                }
+            }
+        })
+    }
+
+    fn tags<'a>(&'a self, tags: &mut Vec<&'a str>) {
+        match self {
+            Node::Number(_) => {}
+            Node::Tag(id) => tags.push(id),
+            Node::Negate(node) | Node::Not(node) => node.tags(tags),
+            Node::Binary(_, left, right) => {
+                left.tags(tags);
+                right.tags(tags);
+            }
+            Node::Call(_, arguments) => arguments.iter().for_each(|node| node.tags(tags)),
+        }
+    }
+}
+
+/// Arithmetic and logical expression over the values of other tags, referenced
+/// by their fully qualified name (`device/tag`). The logical operators give 1
+/// when true and 0 when false, any value other than 0 is true.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression(Node);
+
+impl Expression {
+    /// Computes the expression with the values given by `value_of` for the
+    /// tags. The result must be a finite number.
+    pub fn evaluate(&self, value_of: &dyn Fn(&str) -> Option<f64>) -> Result<f64, String> {
+        let result = self.0.evaluate(value_of)?;
+        match result.is_finite() {
+            true => Ok(result),
+            false => Err(format!("The result {} is not a finite number.", result)),
+        }
+    }
+
+    /// Ids of the tags used in the expression, without repetitions.
+    pub fn dependencies(&self) -> Vec<String> {
+        let mut tags = Vec::new();
+        self.0.tags(&mut tags);
+        let mut dependencies: Vec<String> = Vec::new();
+        for tag in tags {
+            if !dependencies.iter().any(|d| d == tag) {
+                dependencies.push(tag.to_string());
+            }
+        }
+        dependencies
+    }
+}
+
+impl FromStr for Expression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            position: 0,
+        };
+        let node = parser.binary(0)?;
+        match parser.peek() {
+            None => Ok(Expression(node)),
+            Some(token) => Err(format!("Unexpected {:?} in {}.", token, s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Expression;
+
+    fn evaluate(text: &str) -> Result<f64, String> {
+        let values = |id: &str| match id {
+            "meter/P1" => Some(1.5),
+            "meter/P2" => Some(-2.0),
+            "plant 1/Flow-A" => Some(16.0),
+            _ => None,
+        };
+        text.parse::<Expression>()?.evaluate(&values)
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(Ok(7.0), evaluate("1 + 2 * 3"));
+        assert_eq!(Ok(9.0), evaluate("(1 + 2) * 3"));
+        assert_eq!(Ok(1.0), evaluate("10 - 4 - 5"));
+        assert_eq!(Ok(-0.5), evaluate("meter/P1 + meter/P2"));
+        assert_eq!(Ok(2.0), evaluate("abs(meter/P2)"));
+        assert_eq!(Ok(4.0), evaluate("sqrt([plant 1/Flow-A])"));
+        assert_eq!(Ok(-2.0), evaluate("min(meter/P1, meter/P2, 0)"));
+        assert_eq!(Ok(1.5), evaluate("max(meter/P1, meter/P2)"));
+        assert_eq!(Ok(1.0), evaluate("meter/P1 > 1 && !(meter/P2 >= 0)"));
+        assert_eq!(Ok(0.0), evaluate("meter/P1 == 1 || meter/P2 != -2"));
+        assert_eq!(Ok(1.0), evaluate("7 % 2"));
+        assert_eq!(Ok(0.5), evaluate("2/4"));
+        assert_eq!(Ok(0.75), evaluate("meter/P1/2"));
+        assert_eq!(Ok(-3.0), evaluate("meter/P2/meter/P1*3+1"));
+        // Only the chosen branch is evaluated, the missing tag is not needed.
+        assert_eq!(Ok(3.0), evaluate("if(meter/P2 < 0, 3, meter/Unknown)"));
+        assert_eq!(Ok(1.0), evaluate("1 || meter/Unknown"));
+
+        assert!(evaluate("meter/Unknown * 2").is_err());
+        assert!(evaluate("1 / (meter/P1 - 1.5)").is_err());
+        assert!(evaluate("sqrt(meter/P2)").is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for text in [
+            "",
+            "1 +",
+            "(1 + 2",
+            "pow(2, 3)",
+            "abs(1, 2)",
+            "if(1, 2)",
+            "1 2",
+            "a & b",
+            "[dev/Tag",
+        ] {
+            assert!(text.parse::<Expression>().is_err(), "{}", text);
+        }
+        let expression: Expression = "meter/P1 + max(meter/P1, [plant 1/Flow-A])"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            vec!["meter/P1".to_string(), "plant 1/Flow-A".to_string()],
+            expression.dependencies()
+        );
+    }
+}
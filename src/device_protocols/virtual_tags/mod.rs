@@ -0,0 +1,152 @@
+pub mod expression;
+
+use crate::config_files::ini_parser;
+use crate::device_protocols::safety::List;
+use crate::models::aggregation::Aggregate;
+use crate::models::cache;
+use crate::models::deadband::DeadbandType;
+use crate::models::device::DeviceError;
+use crate::models::tag::{TagResponse, TagValue};
+use crate::{gen_readable_struct, DeviceProtocols};
+use expression::Expression;
+use std::collections::HashSet;
+
+gen_readable_struct!(
+    struct Connection {
+        name: String,
+    }
+);
+
+gen_readable_struct!(
+    struct Tag {
+        name: String,
+        expression: Expression,
+        aggregate_window_s: u64 = 0,
+        aggregate: List<Aggregate> = List::default(),
+        publish_on_change: bool = false,
+        deadband: f32 = 0.0,
+        deadband_type: DeadbandType = DeadbandType::Absolute,
+        heartbeat_min: u64 = 0,
+    }
+);
+
+pub fn reader<F>(constructor: F, path: &str) -> Vec<DeviceProtocols>
+where
+    F: Fn(Connection, Tag) -> DeviceProtocols,
+{
+    let connection = ini_parser::read_file::<Connection>(&format!("{}/connection.ini", path))
+        .into_iter()
+        .next()
+        .unwrap();
+    ini_parser::read_file::<Tag>(&format!("{}/publishers.ini", path))
+        .into_iter()
+        .map(|tag| constructor(connection.to_owned(), tag))
+        .collect()
+}
+
+/// Checks that every tag used by the virtual tags exists, so a typo in an
+/// expression is found at startup instead of as a decode error of every poll.
+pub fn check_dependencies(devices: &[DeviceProtocols]) -> Result<(), String> {
+    let ids: HashSet<String> = devices.iter().map(|dev| dev.id()).collect();
+    for dev in devices {
+        for id in dev.dependencies().unwrap_or_default() {
+            if !ids.contains(&id) {
+                return Err(format!(
+                    "The tag {} used by the virtual tag {} cannot be found.",
+                    id,
+                    dev.id()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Evaluates the expression of the tag with the last values read of the tags
+/// it depends on, as long as their last read did not fail and they are not
+/// outdated.
+pub fn read(con: &Connection, tag: &Tag) -> Result<TagResponse, DeviceError> {
+    let value_of = |id: &str| cache::get_valid(id).map(|value| value.to_f64());
+    let value = tag
+        .expression
+        .evaluate(&value_of)
+        .map_err(DeviceError::Decode)?;
+    Ok(TagResponse {
+        id: format!("{}/{}", con.name, tag.name),
+        value: TagValue::F32(value as f32),
+        cached: false,
+        timestamp: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Connection, Tag};
+    use std::collections::HashMap;
+
+    // Virtual tag of the device `plant` built as if it was read from its files.
+    fn virtual_tag(name: &str, expression: &str) -> (Connection, Tag) {
+        let section = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        (
+            Connection::try_from(section(&[("name", "plant")])).unwrap(),
+            Tag::try_from(section(&[("name", name), ("expression", expression)])).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_offline_dependency() {
+        use super::read;
+        use crate::cloud_protocols::mqtt::tests::unreachable_device;
+        use crate::device_protocols::{bus::Priority, modbus::shared::Command, Mode};
+        use crate::models::cache;
+        use crate::models::tag::{TagResponse, TagValue};
+        use std::time::Duration;
+
+        let (con, tag) = virtual_tag("Double", "mqtt_test/Source * 2");
+
+        cache::update(
+            &TagResponse {
+                id: "mqtt_test/Source".to_string(),
+                value: TagValue::I32(21),
+                cached: false,
+                timestamp: None,
+            },
+            Duration::from_secs(60),
+        );
+        assert_eq!(TagValue::F32(42.0), read(&con, &tag).unwrap().value);
+
+        // The device stops answering, so its last value is no longer used.
+        let dev = unreachable_device("Source", Command::Holding, Mode::Read);
+        assert!(dev.read(Priority::Poll).await.is_err());
+        assert_eq!("decode", read(&con, &tag).unwrap_err().code());
+    }
+
+    #[test]
+    fn test_check_dependencies() {
+        use super::check_dependencies;
+        use crate::cloud_protocols::mqtt::tests::unreachable_device;
+        use crate::device_protocols::{modbus::shared::Command, DeviceProtocols, Mode};
+
+        let total = |expression: &str| {
+            let (con, tag) = virtual_tag("Total", expression);
+            DeviceProtocols::Virtual(con, tag)
+        };
+        let source = unreachable_device("Power", Command::Holding, Mode::Read);
+
+        let devices = [source.to_owned(), total("mqtt_test/Power/2")];
+        assert_eq!(Ok(()), check_dependencies(&devices));
+        let devices = [source, total("mqtt_test/Energy + 1")];
+        assert_eq!(
+            Err(
+                "The tag mqtt_test/Energy used by the virtual tag plant/Total cannot be found."
+                    .to_string()
+            ),
+            check_dependencies(&devices)
+        );
+    }
+}
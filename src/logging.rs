@@ -0,0 +1,103 @@
+use std::path::Path;
+use tracing::{Event, Level, Subscriber};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::format::{Format, Full, Writer};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, Clone, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line, with the fields of the current spans.
+    Json,
+    /// Lines prefixed with the syslog priority (i.e. `<4>`), as expected by journald
+    /// when reading the stderr of a service.
+    Journald,
+}
+
+// Text format without time, journald adds its own timestamp.
+struct JournaldFormat(Format<Full, ()>);
+
+impl<S, N> FormatEvent<S, N> for JournaldFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        let priority = match *event.metadata().level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            _ => 7,
+        };
+        write!(writer, "<{}>", priority)?;
+        self.0.format_event(ctx, writer, event)
+    }
+}
+
+/// Starts the logger. The level is taken from `level` or, if it is not given,
+/// from the RUST_LOG variable (i.e. `info,iot_gateway=debug`). When `file` is
+/// given the logs are written there, rotated daily keeping `max_files` files.
+/// The returned guard flushes the file when dropped.
+pub fn init(
+    level: Option<&str>,
+    format: &LogFormat,
+    file: Option<&str>,
+    max_files: usize,
+) -> Result<Option<WorkerGuard>, String> {
+    let filter = match level {
+        Some(level) => EnvFilter::try_new(level),
+        None => EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new("info")),
+    }
+    .map_err(|err| format!("Invalid log level: {}", err))?;
+
+    let (writer, guard) = match file {
+        Some(file) => {
+            let path = Path::new(file);
+            let folder = path.parent().unwrap_or(Path::new("."));
+            let prefix = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or(file);
+            std::fs::create_dir_all(folder)
+                .map_err(|err| format!("The log folder of {} cannot be created: {}", file, err))?;
+            let appender = RollingFileAppender::builder()
+                .rotation(Rotation::DAILY)
+                .filename_prefix(prefix)
+                .max_log_files(max_files.max(1))
+                .build(folder)
+                .map_err(|err| format!("The log file {} cannot be opened: {}", file, err))?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stderr), None),
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(file.is_none() && matches!(format, LogFormat::Text));
+    let result = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init(),
+        LogFormat::Journald => {
+            let format = Format::default().without_time().with_level(false);
+            builder.event_format(JournaldFormat(format)).try_init()
+        }
+    };
+    result.map_err(|err| err.to_string())?;
+    Ok(guard)
+}
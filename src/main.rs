@@ -1,42 +1,130 @@
 mod cloud_protocols;
 mod config_files;
 mod device_protocols;
+mod logging;
 mod models;
 mod running_modes;
 
-use clap::Parser;
-use cloud_protocols::mqtt::{connect_broker_subscribing_to_commands, send_message};
+use clap::{ArgGroup, Parser, Subcommand};
+use cloud_protocols::mqtt::{
+    command_context, connect_broker_subscribing_to_commands, send_message,
+};
+use cloud_protocols::topics::Publication;
+use cloud_protocols::{start_metrics_server, start_modbus_server, start_rest_api};
+use config_files::ini_parser::PROFILES_FOLDER;
+use device_protocols::modbus::import::import_register_map;
+use device_protocols::safety::load_policy;
+use device_protocols::virtual_tags::check_dependencies;
 use device_protocols::DeviceProtocols;
+use logging::LogFormat;
+use models::history::load_history;
 use running_modes::{daemon_mode, tag_one_shot_read};
 use std::sync::Arc;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Tag read once, as `device/tag` or only its name when it is unique.
     #[arg(short, long)]
     tag_name: Option<String>,
 
     #[arg(short, long, default_value_t = 1)]
     retry: u32,
+
+    /// Log level or filter (i.e. `debug` or `info,iot_gateway=trace`), by default
+    /// taken from RUST_LOG or `info`.
+    #[arg(long)]
+    log_level: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// File where the logs are written instead of stderr, rotated daily.
+    #[arg(long)]
+    log_file: Option<String>,
+
+    /// Rotated log files kept.
+    #[arg(long, default_value_t = 7)]
+    log_max_files: usize,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Generates the tags of a device or a profile from a CSV Modbus register map.
+    #[command(group(ArgGroup::new("target").required(true).args(["device", "profile"])))]
+    Import {
+        /// CSV file with the columns name, address, length, command, data_type, swap,
+        /// multiplier, unit and mode.
+        csv: String,
+
+        /// Device folder where the publishers.ini is generated.
+        #[arg(short, long)]
+        device: Option<String>,
+
+        /// Name of the profile generated in the profiles folder.
+        #[arg(short, long)]
+        profile: Option<String>,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let devices = Arc::new(DeviceProtocols::from_ini_files());
     let arguments = Args::parse();
+    let _log_guard = logging::init(
+        arguments.log_level.as_deref(),
+        &arguments.log_format,
+        arguments.log_file.as_deref(),
+        arguments.log_max_files,
+    )?;
+
+    if let Some(Command::Import {
+        csv,
+        device,
+        profile,
+    }) = arguments.command
+    {
+        let output = match (device, profile) {
+            (Some(device), _) => format!("{}/publishers.ini", device),
+            (_, Some(profile)) => format!("{}/{}.ini", PROFILES_FOLDER, profile),
+            _ => unreachable!(),
+        };
+        if let Err(errors) = import_register_map(&csv, &output) {
+            errors.iter().for_each(|err| eprintln!("{}", err));
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    load_policy();
+    load_history();
+    let devices = DeviceProtocols::from_ini_files();
+    check_dependencies(&devices).unwrap_or_else(|err| panic!("Invalid virtual tag. {}", err));
+    let devices = Arc::new(devices);
 
     if let Some(tag_name) = arguments.tag_name {
         let return_value = tag_one_shot_read(devices, &tag_name, arguments.retry).await;
         print!("{}", return_value);
     } else {
-        let (mqtt_client, base_topic) = connect_broker_subscribing_to_commands(devices.clone())
+        start_modbus_server(devices.clone())?;
+        start_metrics_server()?;
+        let context = command_context(devices.clone());
+        start_rest_api(context.clone())?;
+
+        let (mqtt_client, topics) = connect_broker_subscribing_to_commands(context)
             .expect("There is a problem initializing Mqtt Conection");
 
-        let sender = move |name: &str, msg: &str| {
-            let topic = format!("{}/{}", base_topic, name);
-            send_message(&mqtt_client, &topic, msg.as_ref())
+        let sender = move |publication: &Publication| {
+            send_message(
+                &mqtt_client,
+                &publication.topic,
+                &publication.payload,
+                publication.retain,
+            )
         };
-        daemon_mode(devices, sender).await;
+        daemon_mode(devices, topics, sender).await;
     }
     Ok(())
 }
@@ -0,0 +1,293 @@
+use crate::gen_matcher;
+use chrono::{DateTime, Utc};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+
+gen_matcher!(
+    enum Aggregate {
+        Min,
+        Max,
+        Mean,
+        Sum,
+        Last,
+        Count,
+        StdDev,
+        Twa,
+    }
+);
+
+const ALL: [Aggregate; 8] = [
+    Aggregate::Min,
+    Aggregate::Max,
+    Aggregate::Mean,
+    Aggregate::Sum,
+    Aggregate::Last,
+    Aggregate::Count,
+    Aggregate::StdDev,
+    Aggregate::Twa,
+];
+
+/// End of the window of `length` that holds `time`, the windows are aligned to
+/// the clock (i.e. a 60 s window starts every minute).
+pub fn window_end(time: DateTime<Utc>, length: Duration) -> DateTime<Utc> {
+    let length_ms = (length.as_millis() as i64).max(1);
+    let start_ms = time.timestamp_millis() - time.timestamp_millis().rem_euclid(length_ms);
+    DateTime::from_timestamp_millis(start_ms + length_ms).unwrap_or(time)
+}
+
+/// Values of a tag in a window aligned to the clock.
+#[derive(Debug, Clone)]
+struct Window {
+    functions: Vec<Aggregate>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    count: u64,
+    // Reads of the tag that failed during the window.
+    failed: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+    // Running mean and sum of squared differences (Welford).
+    mean: f64,
+    m2: f64,
+    // Last value and since when it holds, carried from the previous window.
+    last: Option<(DateTime<Utc>, f64)>,
+    // Integral of the value over the time it is known, in value * ms.
+    integral: f64,
+    covered_ms: i64,
+}
+
+impl Window {
+    fn new(
+        functions: Vec<Aggregate>,
+        length: Duration,
+        time: DateTime<Utc>,
+        carried: Option<f64>,
+    ) -> Self {
+        let end = window_end(time, length);
+        let start = end - chrono::Duration::milliseconds((length.as_millis() as i64).max(1));
+        Window {
+            functions,
+            start,
+            end,
+            count: 0,
+            failed: 0,
+            sum: 0.0,
+            min: f64::MAX,
+            max: f64::MIN,
+            mean: 0.0,
+            m2: 0.0,
+            last: carried.map(|value| (start, value)),
+            integral: 0.0,
+            covered_ms: 0,
+        }
+    }
+
+    fn hold_until(&mut self, time: DateTime<Utc>) {
+        if let Some((since, value)) = self.last {
+            let elapsed_ms = (time - since).num_milliseconds().max(0);
+            self.integral += value * elapsed_ms as f64;
+            self.covered_ms += elapsed_ms;
+        }
+    }
+
+    fn add(&mut self, time: DateTime<Utc>, value: f64) {
+        self.hold_until(time);
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+        self.last = Some((time, value));
+    }
+
+    /// Aggregates of the window, None when it has no reads. A window whose
+    /// reads have all failed only has the count of failures.
+    fn close(&mut self, id: &str) -> Option<Value> {
+        if self.count == 0 && self.failed == 0 {
+            return None;
+        }
+        self.hold_until(self.end);
+        let last = self.last.map(|(_, value)| value).unwrap_or_default();
+
+        let mut aggregates = Map::new();
+        aggregates.insert("id".to_string(), id.into());
+        aggregates.insert("start".to_string(), self.start.to_rfc3339().into());
+        aggregates.insert("end".to_string(), self.end.to_rfc3339().into());
+        aggregates.insert("failed".to_string(), self.failed.into());
+        let functions = match self.count {
+            0 => &[][..],
+            _ => &self.functions[..],
+        };
+        for function in functions {
+            let (name, value): (&str, Value) = match function {
+                Aggregate::Min => ("min", self.min.into()),
+                Aggregate::Max => ("max", self.max.into()),
+                Aggregate::Mean => ("mean", self.mean.into()),
+                Aggregate::Sum => ("sum", self.sum.into()),
+                Aggregate::Last => ("last", last.into()),
+                Aggregate::Count => ("count", self.count.into()),
+                Aggregate::StdDev => ("stddev", (self.m2 / self.count as f64).sqrt().into()),
+                Aggregate::Twa => match self.covered_ms {
+                    0 => ("twa", last.into()),
+                    covered_ms => ("twa", (self.integral / covered_ms as f64).into()),
+                },
+            };
+            aggregates.insert(name.to_string(), value);
+        }
+        Some(Value::Object(aggregates))
+    }
+}
+
+/// Windows of the aggregated tags of a device, indexed by the tag id.
+#[derive(Debug, Default)]
+pub struct Aggregator {
+    windows: HashMap<String, Window>,
+}
+
+impl Aggregator {
+    /// Adds a value read at `time` to the window of the tag. No functions
+    /// means all of them.
+    pub fn add(
+        &mut self,
+        id: &str,
+        length: Duration,
+        functions: &[Aggregate],
+        time: DateTime<Utc>,
+        value: f64,
+    ) {
+        self.window(id, length, functions, time).add(time, value);
+    }
+
+    /// Counts a failed read at `time` in the window of the tag.
+    pub fn fail(
+        &mut self,
+        id: &str,
+        length: Duration,
+        functions: &[Aggregate],
+        time: DateTime<Utc>,
+    ) {
+        self.window(id, length, functions, time).failed += 1;
+    }
+
+    fn window(
+        &mut self,
+        id: &str,
+        length: Duration,
+        functions: &[Aggregate],
+        time: DateTime<Utc>,
+    ) -> &mut Window {
+        let functions = match functions.is_empty() {
+            true => ALL.to_vec(),
+            false => functions.to_vec(),
+        };
+        self.windows
+            .entry(id.to_string())
+            .or_insert_with(|| Window::new(functions, length, time, None))
+    }
+
+    /// Closes the windows ended at `now`, returning their aggregates. The
+    /// next window of each tag starts with its last value.
+    pub fn close_ended(&mut self, now: DateTime<Utc>) -> Vec<Value> {
+        let mut closed = Vec::new();
+        for (id, window) in self.windows.iter_mut() {
+            if window.end > now {
+                continue;
+            }
+            closed.extend(window.close(id));
+            let length = (window.end - window.start).to_std().unwrap_or_default();
+            let carried = window.last.map(|(_, value)| value);
+            *window = Window::new(window.functions.to_owned(), length, now, carried);
+        }
+        closed.sort_by(|a, b| a["id"].as_str().cmp(&b["id"].as_str()));
+        closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_aggregator() {
+        use super::{Aggregate, Aggregator};
+        use chrono::{DateTime, Duration, Utc};
+
+        let start = DateTime::<Utc>::from_timestamp(1_700_000_040, 0).unwrap();
+        let at = |seconds: i64| start + Duration::seconds(seconds);
+        let window = std::time::Duration::from_secs(10);
+        let mut aggregator = Aggregator::default();
+
+        // 2 during 5 s, 4 during 3 s and 8 during the last 2 s of the window.
+        for (second, value) in [(0, 2.0), (5, 4.0), (8, 8.0)] {
+            aggregator.add("dev/Power", window, &[], at(second), value);
+            aggregator.add("dev/Flow", window, &[Aggregate::Max], at(second), value);
+        }
+        assert!(aggregator.close_ended(at(9)).is_empty());
+
+        let closed = aggregator.close_ended(at(10));
+        assert_eq!(2, closed.len());
+        assert_eq!(
+            serde_json::json!({
+                "id": "dev/Flow",
+                "start": "2023-11-14T22:14:00+00:00",
+                "end": "2023-11-14T22:14:10+00:00",
+                "failed": 0,
+                "max": 8.0,
+            }),
+            closed[0]
+        );
+        let power = &closed[1];
+        assert_eq!(2.0, power["min"]);
+        assert_eq!(8.0, power["max"]);
+        assert_eq!(14.0, power["sum"]);
+        assert_eq!(8.0, power["last"]);
+        assert_eq!(3, power["count"]);
+        assert!((power["mean"].as_f64().unwrap() - 14.0 / 3.0).abs() < 1e-9);
+        assert!((power["stddev"].as_f64().unwrap() - 2.494438).abs() < 1e-6);
+        // (2 * 5 + 4 * 3 + 8 * 2) / 10
+        assert!((power["twa"].as_f64().unwrap() - 3.8).abs() < 1e-9);
+
+        // The last value holds in the next window until a new one is read.
+        aggregator.add("dev/Power", window, &[], at(15), 0.0);
+        let closed = aggregator.close_ended(at(21));
+        assert_eq!(1, closed.len());
+        assert_eq!(1, closed[0]["count"]);
+        assert!((closed[0]["twa"].as_f64().unwrap() - 4.0).abs() < 1e-9);
+        assert!(aggregator.close_ended(at(40)).is_empty());
+    }
+
+    #[test]
+    fn test_aggregator_failures() {
+        use super::{window_end, Aggregate, Aggregator};
+        use chrono::{DateTime, Duration, Utc};
+
+        let start = DateTime::<Utc>::from_timestamp(1_700_000_040, 0).unwrap();
+        let at = |seconds: i64| start + Duration::seconds(seconds);
+        let window = std::time::Duration::from_secs(10);
+        assert_eq!(at(10), window_end(at(0), window));
+        assert_eq!(at(10), window_end(at(9), window));
+        assert_eq!(at(20), window_end(at(10), window));
+
+        let mut aggregator = Aggregator::default();
+        aggregator.fail("dev/Power", window, &[Aggregate::Max], at(0));
+        aggregator.add("dev/Power", window, &[Aggregate::Max], at(5), 3.0);
+        let closed = aggregator.close_ended(at(10));
+        assert_eq!(1, closed[0]["failed"]);
+        assert_eq!(3.0, closed[0]["max"]);
+
+        // Without any value only the failures are published.
+        aggregator.fail("dev/Power", window, &[Aggregate::Max], at(12));
+        aggregator.fail("dev/Power", window, &[Aggregate::Max], at(15));
+        assert_eq!(
+            vec![serde_json::json!({
+                "id": "dev/Power",
+                "start": "2023-11-14T22:14:10+00:00",
+                "end": "2023-11-14T22:14:20+00:00",
+                "failed": 2,
+            })],
+            aggregator.close_ended(at(20))
+        );
+    }
+}
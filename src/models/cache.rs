@@ -0,0 +1,143 @@
+use super::tag::{TagResponse, TagValue};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::{LazyLock, RwLock};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+struct CachedValue {
+    value: TagValue,
+    timestamp: DateTime<Utc>,
+    // Monotonic time of the read, the age is not affected by clock changes.
+    read_at: Instant,
+    // Age after which the value is no longer valid to compute other tags.
+    valid_for: Duration,
+    // The last read of the tag failed after this value was read.
+    failed: bool,
+}
+
+// Last value read of every tag, indexed by the TagResponse id (device/tag).
+static CACHE: LazyLock<RwLock<HashMap<String, CachedValue>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Stores the value read, it is valid for the virtual tags during `valid_for`.
+pub fn update(response: &TagResponse, valid_for: Duration) {
+    CACHE.write().unwrap().insert(
+        response.id.to_owned(),
+        CachedValue {
+            value: response.value.to_owned(),
+            timestamp: Utc::now(),
+            read_at: Instant::now(),
+            valid_for,
+            failed: false,
+        },
+    );
+}
+
+/// Flags the last value of the tag as outdated by a failed read, it is still
+/// served by `get` until the next value is read.
+pub fn mark_failed(id: &str) {
+    if let Some(cached) = CACHE.write().unwrap().get_mut(id) {
+        cached.failed = true;
+    }
+}
+
+pub fn get(id: &str) -> Option<TagValue> {
+    CACHE
+        .read()
+        .unwrap()
+        .get(id)
+        .map(|cached| cached.value.to_owned())
+}
+
+/// The cached value of the tag while it is valid, that is, its last read did
+/// not fail and it is not older than its validity.
+pub fn get_valid(id: &str) -> Option<TagValue> {
+    CACHE
+        .read()
+        .unwrap()
+        .get(id)
+        .filter(|cached| !cached.failed && cached.read_at.elapsed() <= cached.valid_for)
+        .map(|cached| cached.value.to_owned())
+}
+
+/// The cached value of the tag if it was read less than `max_age` ago and its
+/// last read did not fail, flagged as cached and with the time of the read.
+pub fn get_fresh(id: &str, max_age: Duration) -> Option<TagResponse> {
+    let cache = CACHE.read().unwrap();
+    let cached = cache
+        .get(id)
+        .filter(|cached| !cached.failed && cached.read_at.elapsed() <= max_age)?;
+    Some(TagResponse {
+        id: id.to_string(),
+        value: cached.value.to_owned(),
+        cached: true,
+        timestamp: Some(cached.timestamp.to_rfc3339()),
+    })
+}
+
+/// Every cached value, sorted by id.
+pub fn all() -> Vec<(String, TagValue)> {
+    let mut values: Vec<(String, TagValue)> = CACHE
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(id, cached)| (id.to_owned(), cached.value.to_owned()))
+        .collect();
+    values.sort_by(|a, b| a.0.cmp(&b.0));
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_get_fresh() {
+        use super::{get_fresh, mark_failed, update};
+        use crate::models::tag::{TagResponse, TagValue};
+        use std::time::Duration;
+
+        assert!(get_fresh("cache_test/Temp", Duration::from_secs(60)).is_none());
+        update(
+            &TagResponse {
+                id: "cache_test/Temp".to_string(),
+                value: TagValue::F32(21.5),
+                cached: false,
+                timestamp: None,
+            },
+            Duration::MAX,
+        );
+        let cached = get_fresh("cache_test/Temp", Duration::from_secs(60)).unwrap();
+        assert_eq!(TagValue::F32(21.5), cached.value);
+        assert!(cached.cached && cached.timestamp.is_some());
+
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(get_fresh("cache_test/Temp", Duration::from_millis(1)).is_none());
+
+        // A value outdated by a failed read is not fresh.
+        mark_failed("cache_test/Temp");
+        assert!(get_fresh("cache_test/Temp", Duration::from_secs(60)).is_none());
+    }
+
+    #[test]
+    fn test_get_valid() {
+        use super::{get, get_valid, mark_failed, update};
+        use crate::models::tag::{TagResponse, TagValue};
+        use std::time::Duration;
+
+        let response = TagResponse {
+            id: "cache_test/Flow".to_string(),
+            value: TagValue::F32(3.5),
+            cached: false,
+            timestamp: None,
+        };
+        update(&response, Duration::from_secs(60));
+        assert_eq!(Some(TagValue::F32(3.5)), get_valid("cache_test/Flow"));
+        mark_failed("cache_test/Flow");
+        assert_eq!(None, get_valid("cache_test/Flow"));
+        assert_eq!(Some(TagValue::F32(3.5)), get("cache_test/Flow"));
+
+        update(&response, Duration::from_millis(1));
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(None, get_valid("cache_test/Flow"));
+    }
+}
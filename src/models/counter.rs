@@ -0,0 +1,302 @@
+use super::tag::{TagResponse, TagValue};
+use crate::gen_matcher;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+pub const COUNTERS_FILE: &str = "counters.json";
+
+// The state is saved at most once per interval. After a restart the first
+// sample is compared with the last one saved, so no increment is lost.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+gen_matcher!(
+    enum CounterOutput {
+        Total,
+        Delta,
+        RatePerSecond,
+        RatePerHour,
+    }
+);
+
+/// A tag whose value is a monotonic counter that wraps around at `bits`.
+#[derive(Debug, Clone)]
+pub struct Counter {
+    pub bits: u32,
+    pub multiplier: f64,
+    pub output: CounterOutput,
+}
+
+impl Counter {
+    // Value at which the counter wraps around, in engineering units.
+    fn range(&self) -> f64 {
+        2f64.powi(self.bits as i32) * self.multiplier.abs()
+    }
+
+    /// Increment from `previous` to `value`. A decrease is a rollover when the
+    /// counter would have advanced less than half of its range, otherwise the
+    /// device was reset and counted from 0.
+    fn delta(&self, previous: f64, value: f64) -> f64 {
+        if value >= previous {
+            return value - previous;
+        }
+        let wrapped = self.range() - previous + value;
+        match wrapped <= self.range() / 2.0 {
+            true => wrapped,
+            false => value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CounterState {
+    value: f64,
+    total: f64,
+    timestamp_ms: i64,
+}
+
+/// Last sample and accumulated total of every counter, indexed by the tag id.
+#[derive(Debug, Default)]
+pub struct Counters {
+    states: HashMap<String, CounterState>,
+    // Output of the last sample, served to the reads that do not sample.
+    outputs: HashMap<String, f64>,
+    last_save: Option<Instant>,
+}
+
+impl Counters {
+    fn load(path: &str) -> Self {
+        let states = match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|err| {
+                tracing::warn!(error = %err, "The counters file is not valid, starting from zero");
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Counters {
+            states,
+            ..Default::default()
+        }
+    }
+
+    fn save(&mut self, path: &str) -> std::io::Result<()> {
+        // Written aside and renamed, so a crash never leaves a truncated file.
+        let temporary = format!("{}.tmp", path);
+        std::fs::write(&temporary, serde_json::to_string(&self.states)?)?;
+        std::fs::rename(&temporary, path)?;
+        self.last_save = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Adds a sample of the counter read at `time`, returning its output. The
+    /// total starts at the first value read, the delta and the rate at 0.
+    fn sample(&mut self, id: &str, counter: &Counter, value: f64, time: DateTime<Utc>) -> f64 {
+        // A 32 bits counter read as a signed integer is negative over 2^31.
+        let value = value.rem_euclid(counter.range());
+        let timestamp_ms = time.timestamp_millis();
+        let (delta, seconds) = match self.states.get(id) {
+            Some(previous) => (
+                counter.delta(previous.value, value),
+                (timestamp_ms - previous.timestamp_ms) as f64 / 1000.0,
+            ),
+            None => (0.0, 0.0),
+        };
+        let total = match self.states.get(id) {
+            Some(previous) => previous.total + delta,
+            None => value,
+        };
+        self.states.insert(
+            id.to_string(),
+            CounterState {
+                value,
+                total,
+                timestamp_ms,
+            },
+        );
+
+        let rate = match seconds > 0.0 {
+            true => delta / seconds,
+            false => 0.0,
+        };
+        let output = match counter.output {
+            CounterOutput::Total => total,
+            CounterOutput::Delta => delta,
+            CounterOutput::RatePerSecond => rate,
+            CounterOutput::RatePerHour => rate * 3600.0,
+        };
+        self.outputs.insert(id.to_string(), output);
+        output
+    }
+}
+
+impl Counters {
+    /// Replaces the value read of a counter tag with the output of its sample.
+    pub fn apply(
+        &mut self,
+        counter: &Counter,
+        response: TagResponse,
+        time: DateTime<Utc>,
+    ) -> TagResponse {
+        let value = self.sample(&response.id, counter, response.value.to_f64(), time);
+        TagResponse {
+            value: output_value(counter, value),
+            ..response
+        }
+    }
+}
+
+// Counters of the gateway, restored from the previous run.
+static COUNTERS: LazyLock<Mutex<Counters>> =
+    LazyLock::new(|| Mutex::new(Counters::load(COUNTERS_FILE)));
+
+/// Replaces the value read of a counter tag with the output of its last
+/// sample, without sampling it, so the reads on demand do not shorten the delta
+/// and the rate of the polls. A counter not sampled yet is sampled.
+pub fn last(counter: &Counter, response: TagResponse) -> TagResponse {
+    let output = COUNTERS.lock().unwrap().outputs.get(&response.id).copied();
+    match output {
+        Some(output) => TagResponse {
+            value: output_value(counter, output),
+            ..response
+        },
+        None => apply(counter, response),
+    }
+}
+
+/// Replaces the value read of a counter tag with its output, saving the state
+/// of the counters when it is due.
+pub fn apply(counter: &Counter, response: TagResponse) -> TagResponse {
+    let mut counters = COUNTERS.lock().unwrap();
+    let response = counters.apply(counter, response, Utc::now());
+    if counters
+        .last_save
+        .is_none_or(|last| last.elapsed() >= SAVE_INTERVAL)
+    {
+        if let Err(err) = counters.save(COUNTERS_FILE) {
+            tracing::error!(error = %err, "The counters cannot be saved");
+        }
+    }
+    response
+}
+
+// The totals outgrow the range of an i32 and the precision of an f32, so only
+// the integer outputs that fit in an i32 are not published as an f64.
+fn output_value(counter: &Counter, value: f64) -> TagValue {
+    let integer = value.fract() == 0.0 && value.abs() <= i32::MAX as f64;
+    match (&counter.output, integer) {
+        (CounterOutput::Total | CounterOutput::Delta, true) => TagValue::I32(value as i32),
+        _ => TagValue::F64(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_counter_sample() {
+        use super::{Counter, CounterOutput, Counters};
+        use chrono::{DateTime, Duration, Utc};
+
+        let start = DateTime::<Utc>::from_timestamp(1_700_000_000, 0).unwrap();
+        let at = |seconds: i64| start + Duration::seconds(seconds);
+        let counter = |output: CounterOutput| Counter {
+            bits: 16,
+            multiplier: 1.0,
+            output,
+        };
+        let (total, delta, rate) = (
+            counter(CounterOutput::Total),
+            counter(CounterOutput::Delta),
+            counter(CounterOutput::RatePerHour),
+        );
+        let mut counters = Counters::default();
+
+        assert_eq!(
+            65000.0,
+            counters.sample("dev/Total", &total, 65000.0, at(0))
+        );
+        assert_eq!(0.0, counters.sample("dev/Delta", &delta, 65000.0, at(0)));
+        assert_eq!(0.0, counters.sample("dev/Rate", &rate, 65000.0, at(0)));
+
+        // Rollover: 65000 -> 65535 -> 0 -> 100 is 636 pulses.
+        assert_eq!(65636.0, counters.sample("dev/Total", &total, 100.0, at(60)));
+        assert_eq!(636.0, counters.sample("dev/Delta", &delta, 100.0, at(60)));
+        assert_eq!(
+            636.0 * 60.0,
+            counters.sample("dev/Rate", &rate, 100.0, at(60))
+        );
+
+        // Reset: the device started again from 0 and counted 20 pulses.
+        assert_eq!(65656.0, counters.sample("dev/Total", &total, 20.0, at(120)));
+        assert_eq!(20.0, counters.sample("dev/Delta", &delta, 20.0, at(120)));
+        // The reads that do not sample are served the output of the last one.
+        assert_eq!(Some(&65656.0), counters.outputs.get("dev/Total"));
+        assert_eq!(Some(&20.0), counters.outputs.get("dev/Delta"));
+
+        // A 32 bits counter decoded as a negative integer.
+        let energy = Counter {
+            bits: 32,
+            multiplier: 0.1,
+            output: CounterOutput::Delta,
+        };
+        counters.sample("dev/Energy", &energy, -10.0, at(0));
+        let delta = counters.sample("dev/Energy", &energy, 5.0, at(1));
+        assert!((delta - 15.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_counter_output_value() {
+        use super::{output_value, Counter, CounterOutput, Counters};
+        use crate::models::tag::TagValue;
+        use chrono::{DateTime, Duration, Utc};
+
+        let start = DateTime::<Utc>::from_timestamp(1_700_000_000, 0).unwrap();
+        let energy = Counter {
+            bits: 32,
+            multiplier: 1.0,
+            output: CounterOutput::Total,
+        };
+        let mut counters = Counters::default();
+        let total = counters.sample("dev/Energy", &energy, 4_294_967_000.0, start);
+        assert_eq!(TagValue::F64(4_294_967_000.0), output_value(&energy, total));
+
+        // The total keeps counting over 2^32 after a rollover.
+        let total = counters.sample("dev/Energy", &energy, 704.0, start + Duration::hours(1));
+        assert_eq!(TagValue::F64(4_294_968_000.0), output_value(&energy, total));
+
+        let scaled = Counter {
+            multiplier: 0.001,
+            ..energy
+        };
+        assert_eq!(
+            TagValue::F64(123_456_789.123),
+            output_value(&scaled, 123_456_789.123)
+        );
+    }
+
+    #[test]
+    fn test_counters_persistence() {
+        use super::{Counter, CounterOutput, Counters};
+        use chrono::Utc;
+
+        let path = std::env::temp_dir().join(format!("counters_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let counter = Counter {
+            bits: 16,
+            multiplier: 1.0,
+            output: CounterOutput::Total,
+        };
+        let mut counters = Counters::default();
+        counters.sample("dev/Total", &counter, 500.0, Utc::now());
+        counters.save(path).unwrap();
+
+        let mut restored = Counters::load(path);
+        assert_eq!(counters.states, restored.states);
+        assert_eq!(
+            510.0,
+            restored.sample("dev/Total", &counter, 10.0, Utc::now())
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
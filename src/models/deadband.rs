@@ -0,0 +1,151 @@
+use super::device::DeviceError;
+use super::tag::TagResponse;
+use crate::gen_matcher;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+gen_matcher!(
+    enum DeadbandType {
+        Absolute,
+        Percent,
+    }
+);
+
+/// Report by exception policy of a tag: a value is published when it differs
+/// from the last one published more than the deadband, or when `heartbeat`
+/// elapses without publishing.
+#[derive(Debug, Clone)]
+pub struct ReportPolicy {
+    pub deadband: f32,
+    pub deadband_type: DeadbandType,
+    pub heartbeat: Option<Duration>,
+}
+
+impl ReportPolicy {
+    fn exceeds(&self, published: f64, value: f64) -> bool {
+        let deadband = self.deadband as f64;
+        let threshold = match self.deadband_type {
+            DeadbandType::Absolute => deadband,
+            DeadbandType::Percent => published.abs() * deadband / 100.0,
+        };
+        (value - published).abs() > threshold
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Reported {
+    Value(f64),
+    // Code of the error, the same error is not published again.
+    Error(&'static str),
+}
+
+/// Last value published of each tag, indexed by the tag id.
+#[derive(Debug, Default)]
+pub struct ExceptionFilter {
+    published: HashMap<String, (Reported, Instant)>,
+}
+
+impl ExceptionFilter {
+    /// Decides if the result read of the tag is published, remembering it if so.
+    pub fn should_publish(
+        &mut self,
+        id: &str,
+        policy: &ReportPolicy,
+        result: &Result<TagResponse, DeviceError>,
+        now: Instant,
+    ) -> bool {
+        let reported = match result {
+            Ok(response) => Reported::Value(response.value.to_f64()),
+            Err(err) => Reported::Error(err.code()),
+        };
+        let heartbeat_due = |at: &Instant| {
+            policy
+                .heartbeat
+                .is_some_and(|h| now.duration_since(*at) >= h)
+        };
+        let publish = match self.published.get(id) {
+            None => true,
+            Some((_, at)) if heartbeat_due(at) => true,
+            Some((Reported::Value(published), _)) => match reported {
+                Reported::Value(value) => policy.exceeds(*published, value),
+                Reported::Error(_) => true,
+            },
+            Some((published, _)) => *published != reported,
+        };
+        if publish {
+            self.published.insert(id.to_string(), (reported, now));
+        }
+        publish
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_exception_filter() {
+        use super::{DeadbandType, ExceptionFilter, ReportPolicy};
+        use crate::models::device::DeviceError;
+        use crate::models::tag::{TagResponse, TagValue};
+        use std::time::{Duration, Instant};
+
+        let value = |value: f32| {
+            Ok(TagResponse {
+                id: "dev/Temp".to_string(),
+                value: TagValue::F32(value),
+                cached: false,
+                timestamp: None,
+            })
+        };
+        let absolute = ReportPolicy {
+            deadband: 0.5,
+            deadband_type: DeadbandType::Absolute,
+            heartbeat: Some(Duration::from_secs(60)),
+        };
+        let start = Instant::now();
+        let at = |seconds: u64| start + Duration::from_secs(seconds);
+        let mut filter = ExceptionFilter::default();
+
+        assert!(filter.should_publish("dev/Temp", &absolute, &value(20.0), at(0)));
+        assert!(!filter.should_publish("dev/Temp", &absolute, &value(20.4), at(1)));
+        // Compared with the last value published, not with the last read.
+        assert!(filter.should_publish("dev/Temp", &absolute, &value(20.6), at(2)));
+        assert!(!filter.should_publish("dev/Temp", &absolute, &value(20.6), at(3)));
+        assert!(filter.should_publish("dev/Temp", &absolute, &value(20.6), at(62)));
+
+        let timeout = Err(DeviceError::Timeout(Duration::from_secs(1)));
+        assert!(filter.should_publish("dev/Temp", &absolute, &timeout, at(63)));
+        assert!(!filter.should_publish("dev/Temp", &absolute, &timeout, at(64)));
+        assert!(filter.should_publish("dev/Temp", &absolute, &value(20.6), at(65)));
+
+        let percent = ReportPolicy {
+            deadband: 10.0,
+            deadband_type: DeadbandType::Percent,
+            heartbeat: None,
+        };
+        assert!(filter.should_publish("dev/Power", &percent, &value(200.0), at(0)));
+        assert!(!filter.should_publish("dev/Power", &percent, &value(219.0), at(1)));
+        assert!(!filter.should_publish("dev/Power", &percent, &value(200.0), at(3600)));
+        assert!(filter.should_publish("dev/Power", &percent, &value(179.0), at(3601)));
+
+        // Without deadband every change is published.
+        let on_change = ReportPolicy {
+            deadband: 0.0,
+            ..percent
+        };
+        assert!(filter.should_publish("dev/State", &on_change, &value(0.0), at(0)));
+        assert!(!filter.should_publish("dev/State", &on_change, &value(0.0), at(1)));
+        assert!(filter.should_publish("dev/State", &on_change, &value(1.0), at(2)));
+
+        // The counter totals change below the precision of an f32.
+        let total = |value: f64| {
+            Ok(TagResponse {
+                id: "dev/Total".to_string(),
+                value: TagValue::F64(value),
+                cached: false,
+                timestamp: None,
+            })
+        };
+        assert!(filter.should_publish("dev/Total", &on_change, &total(4_294_968_000.0), at(0)));
+        assert!(filter.should_publish("dev/Total", &on_change, &total(4_294_968_001.0), at(1)));
+    }
+}
@@ -1,11 +1,141 @@
-use serde::Serialize;
+use crate::models::tag::TagValue;
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+use std::time::Duration;
 use std::{fmt::Debug, str::FromStr};
 
-#[derive(Debug, Clone, Serialize)]
-pub struct WriteError(pub String);
+/// Exception codes that a Modbus device can answer, the ones known by
+/// tokio-modbus.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModbusException {
+    IllegalFunction = 0x01,
+    IllegalDataAddress = 0x02,
+    IllegalDataValue = 0x03,
+    ServerDeviceFailure = 0x04,
+    Acknowledge = 0x05,
+    ServerDeviceBusy = 0x06,
+    MemoryParityError = 0x08,
+    GatewayPathUnavailable = 0x0A,
+    GatewayTargetDevice = 0x0B,
+}
+
+impl ModbusException {
+    pub const ALL: [ModbusException; 9] = [
+        Self::IllegalFunction,
+        Self::IllegalDataAddress,
+        Self::IllegalDataValue,
+        Self::ServerDeviceFailure,
+        Self::Acknowledge,
+        Self::ServerDeviceBusy,
+        Self::MemoryParityError,
+        Self::GatewayPathUnavailable,
+        Self::GatewayTargetDevice,
+    ];
+
+    pub fn code(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::IllegalFunction => "Illegal function",
+            Self::IllegalDataAddress => "Illegal data address",
+            Self::IllegalDataValue => "Illegal data value",
+            Self::ServerDeviceFailure => "Server device failure",
+            Self::Acknowledge => "Acknowledge",
+            Self::ServerDeviceBusy => "Server device busy",
+            Self::MemoryParityError => "Memory parity error",
+            Self::GatewayPathUnavailable => "Gateway path unavailable",
+            Self::GatewayTargetDevice => "Gateway target device failed to respond",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|e| e.name() == name)
+    }
+}
+
+/// Error of a request to a device.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceError {
+    /// The device cannot be reached or the connection failed.
+    Transport(String),
+    /// The device did not answer in time.
+    Timeout(Duration),
+    /// The device circuit breaker is open.
+    Offline(String),
+    /// The device answered with a Modbus exception.
+    Exception(ModbusException),
+    /// The answer of the device cannot be decoded.
+    Decode(String),
+    /// The request is not valid for the tag configuration.
+    Config(String),
+    /// The received command or its value cannot be understood.
+    InvalidCommand(String),
+    /// The write is not allowed by the safety settings of the gateway.
+    Forbidden(String),
+    /// The request has no valid credentials.
+    Unauthorized(String),
+    /// The value read back after a write is not the written one, holds the
+    /// written and the actual values.
+    WriteMismatch(TagValue, TagValue),
+}
+
+impl DeviceError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Transport(_) => "transport",
+            Self::Timeout(_) => "timeout",
+            Self::Offline(_) => "offline",
+            Self::Exception(_) => "modbus_exception",
+            Self::Decode(_) => "decode",
+            Self::Config(_) => "config",
+            Self::InvalidCommand(_) => "invalid_command",
+            Self::Forbidden(_) => "forbidden",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::WriteMismatch(_, _) => "write_mismatch",
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transport(msg)
+            | Self::Decode(msg)
+            | Self::Config(msg)
+            | Self::InvalidCommand(msg)
+            | Self::Forbidden(msg)
+            | Self::Unauthorized(msg) => write!(f, "{}", msg),
+            Self::Timeout(duration) => write!(f, "Timeout after {} ms.", duration.as_millis()),
+            Self::Offline(device) => write!(f, "The device {} is offline.", device),
+            Self::Exception(exception) => {
+                write!(f, "{} (0x{:02X}).", exception.name(), exception.code())
+            }
+            Self::WriteMismatch(written, actual) => {
+                write!(f, "The device holds {} instead of {}.", actual, written)
+            }
+        }
+    }
+}
 
-#[derive(Debug, Clone, Serialize)]
-pub struct ReadError(pub String);
+impl std::error::Error for DeviceError {}
+
+impl Serialize for DeviceError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("code", self.code())?;
+        match self {
+            Self::Exception(exception) => {
+                map.serialize_entry("exception_code", &exception.code())?
+            }
+            Self::WriteMismatch(_, actual) => map.serialize_entry("actual_value", actual)?,
+            _ => {}
+        }
+        map.serialize_entry("message", &self.to_string())?;
+        map.end()
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum ReadFrequency {
@@ -28,14 +158,17 @@ impl FromStr for ReadFrequency {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid read frequency \"{}\", expected i.e. \"5 s\".", s);
         let splitted: Vec<_> = s.split(' ').collect();
-        let ammount = splitted.first().unwrap().parse().unwrap();
-        let &marker = splitted.get(1).unwrap();
+        let (ammount, marker) = match splitted.as_slice() {
+            [ammount, marker] => (ammount.parse().map_err(|_| invalid())?, *marker),
+            _ => return Err(invalid()),
+        };
         match marker {
             "s" => Ok(Self::Seconds(ammount)),
             "m" => Ok(Self::Minutes(ammount)),
             "h" => Ok(Self::Hours(ammount)),
-            _ => unimplemented!("Invalid marker!"),
+            _ => Err(invalid()),
         }
     }
 }
@@ -0,0 +1,415 @@
+use super::device::DeviceError;
+use super::tag::{TagResponse, TagValue};
+use crate::config_files::ini_parser;
+use crate::gen_readable_struct;
+use chrono::{DateTime, Utc};
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+pub const HISTORY_FILE: &str = "history.ini";
+
+// The retention is applied at most once per interval.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+gen_readable_struct!(
+    struct HistoryConfig {
+        database: String = "history.db".to_string(),
+        retention_days: u64 = 30,
+        max_bytes: u64 = 0,
+        max_points: usize = 10000,
+    }
+);
+
+// The values are stored as SQLite integers or reals. A real is an F32 when the
+// f32 holds it exactly, so the F64 counter outputs keep their precision.
+fn stored_value(value: ValueRef) -> rusqlite::Result<TagValue> {
+    Ok(match value {
+        ValueRef::Integer(value) => match i32::try_from(value) {
+            Ok(value) => TagValue::I32(value),
+            Err(_) => TagValue::F64(value as f64),
+        },
+        value => {
+            let value = value.as_f64()?;
+            match value as f32 as f64 == value {
+                true => TagValue::F32(value as f32),
+                false => TagValue::F64(value),
+            }
+        }
+    })
+}
+
+/// Value of a tag stored in the history.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistoryPoint {
+    pub timestamp: String,
+    pub value: TagValue,
+}
+
+/// Values of a query, oldest first. When there are more than `max_points`
+/// it is truncated and `next_from` is the time of the first value left out,
+/// from where the range can be asked again.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistoryPage {
+    pub points: Vec<HistoryPoint>,
+    pub truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_from: Option<String>,
+}
+
+/// Rolling history of the tags in a SQLite database. The rows older than
+/// `retention_days` are deleted and, when `max_bytes` is not 0, also the
+/// oldest ones while the database is bigger.
+pub struct History {
+    connection: Connection,
+    retention: chrono::Duration,
+    max_bytes: u64,
+    max_points: usize,
+    last_prune: Option<Instant>,
+}
+
+impl History {
+    pub fn open(config: &HistoryConfig) -> rusqlite::Result<Self> {
+        let connection = Connection::open(&config.database)?;
+        // The value has no declared type, so integers and floats are kept as they are.
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS history (
+                timestamp_ms INTEGER NOT NULL,
+                tag TEXT NOT NULL,
+                value
+            );
+            CREATE INDEX IF NOT EXISTS history_tag ON history (tag, timestamp_ms);
+            CREATE INDEX IF NOT EXISTS history_time ON history (timestamp_ms);",
+        )?;
+        Ok(History {
+            connection,
+            retention: chrono::Duration::days(config.retention_days as i64),
+            max_bytes: config.max_bytes,
+            max_points: config.max_points,
+            last_prune: None,
+        })
+    }
+
+    pub fn insert(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        values: &[TagResponse],
+    ) -> rusqlite::Result<()> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement = transaction.prepare_cached(
+                "INSERT INTO history (timestamp_ms, tag, value) VALUES (?1, ?2, ?3)",
+            )?;
+            for response in values {
+                let timestamp_ms = timestamp.timestamp_millis();
+                match response.value {
+                    TagValue::F32(value) => {
+                        statement.execute(params![timestamp_ms, response.id, value])?
+                    }
+                    TagValue::I32(value) => {
+                        statement.execute(params![timestamp_ms, response.id, value])?
+                    }
+                    TagValue::F64(value) => {
+                        statement.execute(params![timestamp_ms, response.id, value])?
+                    }
+                };
+            }
+        }
+        transaction.commit()?;
+
+        if self
+            .last_prune
+            .is_none_or(|last| last.elapsed() >= PRUNE_INTERVAL)
+        {
+            self.prune(timestamp)?;
+            self.last_prune = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Values of the tag between `from` and `to` (both included), oldest
+    /// first and up to `max_points`.
+    pub fn query(
+        &self,
+        id: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> rusqlite::Result<HistoryPage> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT timestamp_ms, value FROM history
+             WHERE tag = ?1 AND timestamp_ms BETWEEN ?2 AND ?3
+             ORDER BY timestamp_ms LIMIT ?4",
+        )?;
+        let rows = statement.query_map(
+            params![
+                id,
+                from.timestamp_millis(),
+                to.timestamp_millis(),
+                // One more row tells if the range is truncated.
+                self.max_points as i64 + 1
+            ],
+            |row| {
+                let timestamp_ms: i64 = row.get(0)?;
+                let value = stored_value(row.get_ref(1)?)?;
+                Ok(HistoryPoint {
+                    timestamp: DateTime::from_timestamp_millis(timestamp_ms)
+                        .unwrap_or_default()
+                        .to_rfc3339(),
+                    value,
+                })
+            },
+        )?;
+        let mut points = rows.collect::<rusqlite::Result<Vec<HistoryPoint>>>()?;
+        let next_from = match points.len() > self.max_points {
+            true => points.pop().map(|point| point.timestamp),
+            false => None,
+        };
+        Ok(HistoryPage {
+            points,
+            truncated: next_from.is_some(),
+            next_from,
+        })
+    }
+
+    fn prune(&self, now: DateTime<Utc>) -> rusqlite::Result<()> {
+        let oldest = (now - self.retention).timestamp_millis();
+        self.connection.execute(
+            "DELETE FROM history WHERE timestamp_ms < ?1",
+            params![oldest],
+        )?;
+
+        if self.max_bytes == 0 {
+            return Ok(());
+        }
+        while self.size()? > self.max_bytes {
+            let rows: i64 =
+                self.connection
+                    .query_row("SELECT COUNT(*) FROM history", [], |row| row.get(0))?;
+            if rows == 0 {
+                break;
+            }
+            // A tenth of the rows at a time, the freed pages are reused by the new rows.
+            self.connection.execute(
+                "DELETE FROM history WHERE rowid IN
+                 (SELECT rowid FROM history ORDER BY timestamp_ms LIMIT ?1)",
+                params![(rows / 10).max(1)],
+            )?;
+        }
+        Ok(())
+    }
+
+    // Bytes of the database in use, without the free pages.
+    fn size(&self) -> rusqlite::Result<u64> {
+        let pragma = |name: &str| -> rusqlite::Result<i64> {
+            self.connection
+                .query_row(&format!("PRAGMA {}", name), [], |row| row.get(0))
+        };
+        let used_pages = pragma("page_count")? - pragma("freelist_count")?;
+        Ok((used_pages * pragma("page_size")?) as u64)
+    }
+}
+
+// History of the gateway, enabled by the optional history.ini file.
+static HISTORY: OnceLock<Mutex<History>> = OnceLock::new();
+
+/// Opens the history when history.ini exists. It is called at startup, so an
+/// invalid file or database stops the gateway instead of the first poll.
+pub fn load_history() {
+    if !std::path::Path::new(HISTORY_FILE).exists() {
+        return;
+    }
+    let config = ini_parser::read_file::<HistoryConfig>(HISTORY_FILE)
+        .into_iter()
+        .next()
+        .expect("Invalid history.ini file");
+    let history = History::open(&config).unwrap_or_else(|err| {
+        panic!(
+            "The history database {} cannot be opened: {}",
+            config.database, err
+        )
+    });
+    if HISTORY.set(Mutex::new(history)).is_err() {
+        tracing::warn!("The history was already opened");
+    }
+}
+
+/// Stores the values read in a poll cycle, if the history is enabled.
+pub async fn record(timestamp: DateTime<Utc>, values: Vec<TagResponse>) {
+    let history = match HISTORY.get() {
+        Some(history) if !values.is_empty() => history,
+        _ => return,
+    };
+    let result =
+        tokio::task::spawn_blocking(move || history.lock().unwrap().insert(timestamp, &values))
+            .await;
+    match result {
+        Ok(Err(err)) => tracing::error!(error = %err, "The values cannot be stored in the history"),
+        Err(err) => tracing::error!(error = %err, "The history task failed"),
+        Ok(Ok(())) => {}
+    }
+}
+
+/// Values stored of the tag with the id `device/tag` in a time range.
+pub async fn query(
+    id: String,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<HistoryPage, DeviceError> {
+    let history = match HISTORY.get() {
+        Some(history) => history,
+        None => {
+            return Err(DeviceError::Config(
+                "The history is not enabled.".to_string(),
+            ))
+        }
+    };
+    tokio::task::spawn_blocking(move || history.lock().unwrap().query(&id, from, to))
+        .await
+        .map_err(|err| DeviceError::Config(format!("The history task failed: {}", err)))?
+        .map_err(|err| DeviceError::Config(format!("The history cannot be read: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{History, HistoryConfig, HistoryPage, HistoryPoint};
+    use crate::models::tag::{TagResponse, TagValue};
+    use chrono::{DateTime, Duration, Utc};
+
+    fn response(id: &str, value: TagValue) -> TagResponse {
+        TagResponse {
+            id: id.to_string(),
+            value,
+            cached: false,
+            timestamp: None,
+        }
+    }
+
+    fn open(name: &str, max_bytes: u64) -> (History, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("{}_{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = HistoryConfig {
+            database: path.to_str().unwrap().to_string(),
+            retention_days: 30,
+            max_bytes,
+            max_points: 3,
+        };
+        (History::open(&config).unwrap(), path)
+    }
+
+    #[test]
+    fn test_history_query() {
+        let (mut history, path) = open("history_query", 0);
+        let start = DateTime::<Utc>::from_timestamp(1_700_000_000, 0).unwrap();
+        for second in 0..5 {
+            let values = vec![
+                response("dev/Temp", TagValue::F32(20.5 + second as f32)),
+                response("dev/Count", TagValue::I32(second)),
+            ];
+            history
+                .insert(start + Duration::seconds(second as i64), &values)
+                .unwrap();
+        }
+
+        let points = history
+            .query(
+                "dev/Count",
+                start + Duration::seconds(1),
+                start + Duration::seconds(2),
+            )
+            .unwrap();
+        let expected = vec![
+            HistoryPoint {
+                timestamp: "2023-11-14T22:13:21+00:00".to_string(),
+                value: TagValue::I32(1),
+            },
+            HistoryPoint {
+                timestamp: "2023-11-14T22:13:22+00:00".to_string(),
+                value: TagValue::I32(2),
+            },
+        ];
+        assert_eq!(
+            HistoryPage {
+                points: expected,
+                truncated: false,
+                next_from: None,
+            },
+            points
+        );
+
+        // The 5 values do not fit in one query of 3, the next one starts at the
+        // first value left out.
+        let page = history
+            .query("dev/Temp", start, start + Duration::days(1))
+            .unwrap();
+        assert_eq!(3, page.points.len());
+        assert_eq!(TagValue::F32(20.5), page.points[0].value);
+        assert!(page.truncated);
+        assert_eq!(Some("2023-11-14T22:13:23+00:00"), page.next_from.as_deref());
+        let next_from = DateTime::parse_from_rfc3339(&page.next_from.unwrap()).unwrap();
+        let page = history
+            .query("dev/Temp", next_from.into(), start + Duration::days(1))
+            .unwrap();
+        assert_eq!(TagValue::F32(23.5), page.points[0].value);
+        assert_eq!((2, false), (page.points.len(), page.truncated));
+
+        // The counter outputs over 2^31 or with more digits than an f32 holds.
+        let values = vec![
+            response("dev/Total", TagValue::F64(4_294_968_000.0)),
+            response("dev/Energy", TagValue::F64(123_456_789.123)),
+        ];
+        history.insert(start, &values).unwrap();
+        for value in values {
+            let page = history.query(&value.id, start, start).unwrap();
+            assert_eq!(value.value, page.points[0].value);
+        }
+        assert_eq!(
+            TagValue::F64(5_000_000_000.0),
+            super::stored_value(rusqlite::types::ValueRef::Integer(5_000_000_000)).unwrap()
+        );
+
+        // A month later the old rows are pruned when the new ones are inserted.
+        history.last_prune = None;
+        history
+            .insert(
+                start + Duration::days(31),
+                &[response("dev/Count", TagValue::I32(9))],
+            )
+            .unwrap();
+        let page = history
+            .query("dev/Count", start, start + Duration::days(40))
+            .unwrap();
+        assert_eq!(
+            vec![TagValue::I32(9)],
+            page.points.into_iter().map(|p| p.value).collect::<Vec<_>>()
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_history_max_bytes() {
+        let (mut history, path) = open("history_size", 32 * 1024);
+        let start = Utc::now();
+        for second in 0..300 {
+            history.last_prune = None;
+            let values: Vec<TagResponse> = (0..10)
+                .map(|tag| response(&format!("dev/Temp{}", tag), TagValue::F32(second as f32)))
+                .collect();
+            history
+                .insert(start + Duration::seconds(second), &values)
+                .unwrap();
+        }
+        assert!(history.size().unwrap() <= 32 * 1024);
+        assert!(history
+            .query("dev/Temp0", start, start)
+            .unwrap()
+            .points
+            .is_empty());
+        // The newest values are kept.
+        let last = start + Duration::seconds(299);
+        let page = history.query("dev/Temp0", last, last).unwrap();
+        assert_eq!(TagValue::F32(299.0), page.points[0].value);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+use super::tag::TagValue;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+// Upper bounds, in seconds, of the request latency histogram.
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Metrics {
+    // Indexed by device, operation and result.
+    requests: BTreeMap<(String, &'static str, &'static str), u64>,
+    latency: BTreeMap<(String, &'static str), Histogram>,
+    poll_overruns: BTreeMap<String, u64>,
+    publish_failures: u64,
+    queue_depth: usize,
+}
+
+// Counters of the whole gateway, exposed in the Prometheus text format.
+static METRICS: LazyLock<Mutex<Metrics>> = LazyLock::new(|| Mutex::new(Metrics::default()));
+
+/// Counts a request to a device and its latency, the operation is `read` or `write`.
+pub fn record_request(device: &str, operation: &'static str, elapsed: Duration, success: bool) {
+    let mut metrics = METRICS.lock().unwrap();
+    let result = if success { "success" } else { "failure" };
+    *metrics
+        .requests
+        .entry((device.to_string(), operation, result))
+        .or_default() += 1;
+    metrics
+        .latency
+        .entry((device.to_string(), operation))
+        .or_default()
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Counts a poll cycle that took longer than the read frequency of the device.
+pub fn record_poll_overrun(device: &str) {
+    *METRICS
+        .lock()
+        .unwrap()
+        .poll_overruns
+        .entry(device.to_string())
+        .or_default() += 1;
+}
+
+/// Records the result of a MQTT publish and the messages waiting to be sent.
+pub fn record_publish(success: bool, queue_depth: usize) {
+    let mut metrics = METRICS.lock().unwrap();
+    if !success {
+        metrics.publish_failures += 1;
+    }
+    metrics.queue_depth = queue_depth;
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders the metrics in the Prometheus text format. The `tag_values` are
+/// the last values read, indexed by the `device/tag` id.
+pub fn render(tag_values: &[(String, TagValue)]) -> String {
+    let metrics = METRICS.lock().unwrap();
+    let mut out = String::new();
+
+    out.push_str("# HELP gateway_device_requests_total Requests to the devices.\n");
+    out.push_str("# TYPE gateway_device_requests_total counter\n");
+    for ((device, operation, result), count) in metrics.requests.iter() {
+        let _ = writeln!(
+            out,
+            "gateway_device_requests_total{{device=\"{}\",operation=\"{}\",result=\"{}\"}} {}",
+            escape(device),
+            operation,
+            result,
+            count
+        );
+    }
+
+    out.push_str(
+        "# HELP gateway_request_duration_seconds Latency of the requests to the devices.\n",
+    );
+    out.push_str("# TYPE gateway_request_duration_seconds histogram\n");
+    for ((device, operation), histogram) in metrics.latency.iter() {
+        let labels = format!("device=\"{}\",operation=\"{}\"", escape(device), operation);
+        for (count, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "gateway_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                labels, bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "gateway_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+            labels, histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "gateway_request_duration_seconds_sum{{{}}} {}",
+            labels, histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "gateway_request_duration_seconds_count{{{}}} {}",
+            labels, histogram.count
+        );
+    }
+
+    out.push_str(
+        "# HELP gateway_poll_overruns_total Poll cycles longer than the read frequency.\n",
+    );
+    out.push_str("# TYPE gateway_poll_overruns_total counter\n");
+    for (device, count) in metrics.poll_overruns.iter() {
+        let _ = writeln!(
+            out,
+            "gateway_poll_overruns_total{{device=\"{}\"}} {}",
+            escape(device),
+            count
+        );
+    }
+
+    out.push_str(
+        "# HELP gateway_mqtt_publish_failures_total MQTT messages that could not be published.\n",
+    );
+    out.push_str("# TYPE gateway_mqtt_publish_failures_total counter\n");
+    let _ = writeln!(
+        out,
+        "gateway_mqtt_publish_failures_total {}",
+        metrics.publish_failures
+    );
+
+    out.push_str(
+        "# HELP gateway_mqtt_queue_depth MQTT messages waiting to be sent to the broker.\n",
+    );
+    out.push_str("# TYPE gateway_mqtt_queue_depth gauge\n");
+    let _ = writeln!(out, "gateway_mqtt_queue_depth {}", metrics.queue_depth);
+
+    if !tag_values.is_empty() {
+        out.push_str("# HELP gateway_tag_value Last value read of the tag.\n");
+        out.push_str("# TYPE gateway_tag_value gauge\n");
+        for (id, value) in tag_values {
+            let (device, tag) = id.split_once('/').unwrap_or(("", id));
+            let _ = writeln!(
+                out,
+                "gateway_tag_value{{device=\"{}\",tag=\"{}\"}} {}",
+                escape(device),
+                escape(tag),
+                value.to_f32()
+            );
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_render() {
+        use super::{record_poll_overrun, record_request, render};
+        use crate::models::tag::TagValue;
+        use std::time::Duration;
+
+        record_request("metrics_dev", "read", Duration::from_millis(20), true);
+        record_request("metrics_dev", "read", Duration::from_secs(7), false);
+        record_poll_overrun("metrics_dev");
+
+        let text = render(&[("metrics_dev/Temp \"1\"".to_string(), TagValue::F32(21.5))]);
+        let has = |line: &str| text.lines().any(|l| l == line);
+        assert!(has("gateway_device_requests_total{device=\"metrics_dev\",operation=\"read\",result=\"success\"} 1"));
+        assert!(has("gateway_device_requests_total{device=\"metrics_dev\",operation=\"read\",result=\"failure\"} 1"));
+        assert!(has("gateway_request_duration_seconds_bucket{device=\"metrics_dev\",operation=\"read\",le=\"0.01\"} 0"));
+        assert!(has("gateway_request_duration_seconds_bucket{device=\"metrics_dev\",operation=\"read\",le=\"0.025\"} 1"));
+        assert!(has("gateway_request_duration_seconds_bucket{device=\"metrics_dev\",operation=\"read\",le=\"5\"} 1"));
+        assert!(has("gateway_request_duration_seconds_bucket{device=\"metrics_dev\",operation=\"read\",le=\"+Inf\"} 2"));
+        assert!(has(
+            "gateway_request_duration_seconds_count{device=\"metrics_dev\",operation=\"read\"} 2"
+        ));
+        assert!(has("gateway_poll_overruns_total{device=\"metrics_dev\"} 1"));
+        assert!(has(
+            "gateway_tag_value{device=\"metrics_dev\",tag=\"Temp \\\"1\\\"\"} 21.5"
+        ));
+        assert!(!render(&[]).contains("gateway_tag_value"));
+    }
+}
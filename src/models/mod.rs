@@ -1,2 +1,9 @@
+pub mod aggregation;
+pub mod cache;
+pub mod counter;
+pub mod deadband;
 pub mod device;
+pub mod history;
+pub mod metrics;
+pub mod stream;
 pub mod tag;
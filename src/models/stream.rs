@@ -0,0 +1,145 @@
+use super::device::DeviceError;
+use super::tag::TagValue;
+use crate::device_protocols::health::DeviceState;
+use serde::Serialize;
+use std::sync::LazyLock;
+use tokio::sync::broadcast;
+
+// Events kept for the slow subscribers before they start losing them.
+const CAPACITY: usize = 1024;
+
+/// Measure or event produced by the scheduler.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamEvent {
+    Measure {
+        device: String,
+        tag: String,
+        timestamp: String,
+        value: Result<TagValue, DeviceError>,
+    },
+    Status {
+        device: String,
+        timestamp: String,
+        state: DeviceState,
+    },
+}
+
+impl StreamEvent {
+    /// Checks the event against `device/tag` globs, no filters match every
+    /// event. The status of a device matches the device part of the globs.
+    pub fn matches(&self, filters: &[String]) -> bool {
+        if filters.is_empty() {
+            return true;
+        }
+        match self {
+            StreamEvent::Measure { device, tag, .. } => {
+                let id = format!("{}/{}", device, tag);
+                filters.iter().any(|filter| glob(filter, &id))
+            }
+            StreamEvent::Status { device, .. } => filters.iter().any(|filter| {
+                let device_filter = filter.split('/').next().unwrap_or_default();
+                glob(device_filter, device)
+            }),
+        }
+    }
+}
+
+// Live events of the gateway, nothing is kept when there are no subscribers.
+static CHANNEL: LazyLock<broadcast::Sender<StreamEvent>> =
+    LazyLock::new(|| broadcast::channel(CAPACITY).0);
+
+pub fn publish(event: StreamEvent) {
+    // It only fails when nobody is subscribed.
+    let _ = CHANNEL.send(event);
+}
+
+pub fn subscribe() -> broadcast::Receiver<StreamEvent> {
+    CHANNEL.subscribe()
+}
+
+/// Matches a text with a glob where `*` is any sequence of characters and `?`
+/// any single character.
+pub fn glob(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and of the text when it was found, to backtrack.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_glob() {
+        use super::glob;
+
+        assert!(glob("dev1/Temp", "dev1/Temp"));
+        assert!(!glob("dev1/Temp", "dev1/Temp2"));
+        assert!(glob("dev1/*", "dev1/Temp"));
+        assert!(glob("*/Temp*", "dev2/Temp_R"));
+        assert!(glob("dev?/T*p", "dev3/Temp"));
+        assert!(glob("*", ""));
+        assert!(glob("*a*b", "xaxxab"));
+        assert!(!glob("*a*b", "xaxxa"));
+        assert!(!glob("dev?/*", "dev10/Temp"));
+    }
+
+    #[test]
+    fn test_stream_filters() {
+        use super::{publish, subscribe, StreamEvent};
+        use crate::device_protocols::health::DeviceState;
+        use crate::models::tag::TagValue;
+
+        let measure = StreamEvent::Measure {
+            device: "dev1".to_string(),
+            tag: "Temp".to_string(),
+            timestamp: String::new(),
+            value: Ok(TagValue::F32(21.5)),
+        };
+        let status = StreamEvent::Status {
+            device: "dev1".to_string(),
+            timestamp: String::new(),
+            state: DeviceState::Offline,
+        };
+        let filters = |filters: &[&str]| filters.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+        assert!(measure.matches(&[]));
+        assert!(measure.matches(&filters(&["dev2/*", "dev1/T*"])));
+        assert!(!measure.matches(&filters(&["dev1/Power"])));
+        assert!(status.matches(&filters(&["dev1/Power"])));
+        assert!(!status.matches(&filters(&["dev2/*"])));
+
+        let mut receiver = subscribe();
+        publish(measure);
+        // Other tests publish on the same channel.
+        let json = loop {
+            let json = serde_json::to_value(receiver.try_recv().unwrap()).unwrap();
+            if json["device"] == "dev1" {
+                break json;
+            }
+        };
+        assert_eq!("measure", json["type"]);
+        assert_eq!(21.5, json["value"]["Ok"]["F32"]);
+    }
+}
@@ -4,6 +4,12 @@ use serde::Serialize;
 pub struct TagResponse {
     pub id: String,
     pub value: TagValue,
+    /// Set when the value is served from the cache instead of the device.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub cached: bool,
+    /// When a cached value was read from the device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize)]
@@ -11,14 +17,35 @@ pub enum TagValue {
     F32(f32),
     // U32(u32),
     I32(i32),
+    // Counter outputs, that outgrow the range or the precision of 32 bits.
+    F64(f64),
     // String(String),
 }
 
-impl ToString for TagValue {
-    fn to_string(&self) -> String {
+impl TagValue {
+    pub fn to_f32(&self) -> f32 {
         match self {
-            Self::I32(x) => x.to_string(),
-            Self::F32(x) => x.to_string(),
+            Self::I32(x) => *x as f32,
+            Self::F32(x) => *x,
+            Self::F64(x) => *x as f32,
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Self::I32(x) => *x as f64,
+            Self::F32(x) => *x as f64,
+            Self::F64(x) => *x,
+        }
+    }
+}
+
+impl std::fmt::Display for TagValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::I32(x) => write!(f, "{}", x),
+            Self::F32(x) => write!(f, "{}", x),
+            Self::F64(x) => write!(f, "{}", x),
         }
     }
 }
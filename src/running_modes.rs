@@ -1,42 +1,194 @@
 use crate::cloud_protocols::mqtt::MqttError;
+use crate::cloud_protocols::topics::{Publication, Topics};
+use crate::device_protocols::bus::Priority;
 use crate::device_protocols::Mode;
-use crate::models::device::ReadError;
+use crate::models::aggregation::{self, Aggregator};
+use crate::models::deadband::ExceptionFilter;
+use crate::models::device::DeviceError;
+use crate::models::history;
+use crate::models::metrics;
+use crate::models::stream::{self, StreamEvent};
 use crate::models::tag::TagResponse;
 use crate::DeviceProtocols;
 use futures::future::join_all;
-use std::collections::HashSet;
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio_cron_scheduler::{Job, JobScheduler};
+use tracing::Instrument;
 
-const TAG_REQUEST_SECONDS_TO_TIMEOUT: u64 = 4;
+// What the polls of a device remember between cycles.
+#[derive(Debug, Default)]
+struct PollState {
+    aggregator: Aggregator,
+    exceptions: ExceptionFilter,
+}
 
-async fn job_function(tags_to_read: &Vec<DeviceProtocols>) -> String {
-    let futures = tags_to_read.iter().map(|dev| {
-        tokio::time::timeout(Duration::new(TAG_REQUEST_SECONDS_TO_TIMEOUT, 0), dev.read())
-    });
-    let values: Vec<Result<TagResponse, ReadError>> = join_all(futures)
-        .await
-        .into_iter()
-        .map(|res| -> Result<TagResponse, ReadError> {
-            match res {
-                Ok(val) => match val {
-                    Ok(val) => Ok(val),
-                    Err(err) => Err(err),
-                },
-                Err(err) => Err(ReadError(err.to_string())),
+// Values to publish of a poll, with the name of their tag, and aggregates of
+// the windows ended.
+type PollResult = (
+    Vec<(String, Result<TagResponse, DeviceError>)>,
+    Vec<serde_json::Value>,
+);
+
+// Reads the tags, returning the values to publish and the aggregates.
+async fn job_function(tags_to_read: &[DeviceProtocols], state: &Mutex<PollState>) -> PollResult {
+    let futures = tags_to_read.iter().map(|dev| dev.read(Priority::Poll));
+    let values: Vec<Result<TagResponse, DeviceError>> = join_all(futures).await;
+
+    let now = chrono::Utc::now();
+    let timestamp = now.to_rfc3339();
+    for (dev, value) in tags_to_read.iter().zip(values.iter()) {
+        stream::publish(StreamEvent::Measure {
+            device: dev.device_name(),
+            tag: dev.tag_name(),
+            timestamp: timestamp.to_owned(),
+            value: value.to_owned().map(|response| response.value),
+        });
+    }
+    let read = values
+        .iter()
+        .filter_map(|value| value.as_ref().ok().cloned());
+    history::record(now, read.collect()).await;
+
+    // The aggregated tags only publish their aggregates and the report by
+    // exception ones only their changes.
+    let mut state = state.lock().unwrap();
+    let aggregates = state.aggregator.close_ended(now);
+    let mut measures = Vec::new();
+    for (dev, value) in tags_to_read.iter().zip(values.iter()) {
+        match (dev.aggregation(), dev.report_policy(), value) {
+            (Some((window, functions)), _, Ok(response)) => {
+                let value = response.value.to_f64();
+                state
+                    .aggregator
+                    .add(&response.id, window, &functions, now, value);
+            }
+            // The failures are counted in the aggregates of the window.
+            (Some((window, functions)), _, Err(err)) => {
+                tracing::warn!(tag = %dev.id(), error = %err, "The aggregated tag cannot be read");
+                state.aggregator.fail(&dev.id(), window, &functions, now);
+            }
+            (None, Some(policy), value) => {
+                let exceptions = &mut state.exceptions;
+                if exceptions.should_publish(&dev.id(), &policy, value, Instant::now()) {
+                    measures.push((dev.tag_name(), value.to_owned()));
+                }
+            }
+            (None, None, value) => measures.push((dev.tag_name(), value.to_owned())),
+        }
+    }
+    (measures, aggregates)
+}
+
+// Virtual tags to evaluate after reading the tags, directly or through other
+// virtual tags, grouped by device in the order they are defined.
+fn dependents(
+    devices: &[DeviceProtocols],
+    tags_read: &[DeviceProtocols],
+) -> Vec<(String, Vec<DeviceProtocols>)> {
+    let mut updated: HashSet<String> = tags_read.iter().map(|dev| dev.id()).collect();
+    let mut selected = vec![false; devices.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (dev, selected) in devices.iter().zip(selected.iter_mut()) {
+            let dependencies = dev.dependencies().unwrap_or_default();
+            if !*selected && dependencies.iter().any(|id| updated.contains(id)) {
+                *selected = true;
+                updated.insert(dev.id());
+                changed = true;
             }
-        })
+        }
+    }
+
+    let mut groups: Vec<(String, Vec<DeviceProtocols>)> = Vec::new();
+    for (dev, _) in devices
+        .iter()
+        .zip(selected)
+        .filter(|(_, selected)| *selected)
+    {
+        match groups
+            .iter_mut()
+            .find(|(name, _)| *name == dev.device_name())
+        {
+            Some((_, tags)) => tags.push(dev.to_owned()),
+            None => groups.push((dev.device_name(), vec![dev.to_owned()])),
+        }
+    }
+    groups
+}
+
+fn send_values<F>(
+    send_f: &F,
+    topics: &Topics,
+    device_name: &str,
+    (measures, aggregates): PollResult,
+) where
+    F: Fn(&Publication) -> Result<(), MqttError>,
+{
+    for publication in topics.measures(device_name, &measures) {
+        if let Err(err) = send_f(&publication) {
+            tracing::error!(error = %err, topic = %publication.topic, "The values cannot be sent");
+        }
+    }
+    for publication in topics.aggregates(device_name, &aggregates) {
+        if let Err(err) = send_f(&publication) {
+            tracing::error!(error = %err, topic = %publication.topic, "The aggregates cannot be sent");
+        }
+    }
+}
+
+// Closes the aggregation windows of the tags when they end, otherwise they
+// would wait for the next poll, up to a read period later.
+fn close_windows_on_time<F>(
+    device_name: String,
+    tags: &[DeviceProtocols],
+    state: Arc<Mutex<PollState>>,
+    topics: Topics,
+    send_f: F,
+) where
+    F: Fn(&Publication) -> Result<(), MqttError> + Send + Sync + 'static,
+{
+    let lengths: Vec<Duration> = tags
+        .iter()
+        .filter_map(|dev| dev.aggregation().map(|(window, _)| window))
         .collect();
-    serde_json::to_string(&values).unwrap()
+    if lengths.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            let now = chrono::Utc::now();
+            let end = match lengths
+                .iter()
+                .map(|length| aggregation::window_end(now, *length))
+                .min()
+            {
+                Some(end) => end,
+                None => return,
+            };
+            tokio::time::sleep((end - now).to_std().unwrap_or_default()).await;
+            let now = chrono::Utc::now().max(end);
+            let aggregates = state.lock().unwrap().aggregator.close_ended(now);
+            send_values(&send_f, &topics, &device_name, (Vec::new(), aggregates));
+        }
+    });
 }
 
-pub async fn daemon_mode<F>(devices: Arc<Vec<DeviceProtocols>>, send_f: F) -> !
+pub async fn daemon_mode<F>(devices: Arc<Vec<DeviceProtocols>>, topics: Topics, send_f: F) -> !
 where
-    F: Fn(&str, &str) -> Result<(), MqttError> + Send + Sync + Clone + 'static,
+    F: Fn(&Publication) -> Result<(), MqttError> + Send + Sync + Clone + 'static,
 {
-    let set_of_connections: HashSet<String> =
-        HashSet::from_iter(devices.iter().map(|d| d.device_name()));
+    // The virtual tags are not polled, they are evaluated with the devices.
+    let set_of_connections: HashSet<String> = HashSet::from_iter(
+        devices
+            .iter()
+            .filter(|d| d.dependencies().is_none())
+            .map(|d| d.device_name()),
+    );
+    // A virtual device evaluated after several devices keeps a single state.
+    let mut virtual_states: HashMap<String, Arc<Mutex<PollState>>> = HashMap::new();
 
     let sched = JobScheduler::new().await.unwrap();
 
@@ -51,18 +203,92 @@ where
             })
             .collect();
 
-        let first_device = tags_to_read.get(0).unwrap();
+        // A device with only write tags has nothing to poll.
+        let first_device = match tags_to_read.first() {
+            Some(first_device) => first_device,
+            None => continue,
+        };
         let (seconds, device_name) = (first_device.freq().to_seconds(), first_device.device_name());
         let send_f = send_f.to_owned();
+        let topics = topics.to_owned();
+        let state = Arc::new(Mutex::new(PollState::default()));
+        close_windows_on_time(
+            device_name.to_owned(),
+            &tags_to_read,
+            state.to_owned(),
+            topics.to_owned(),
+            send_f.to_owned(),
+        );
+        let dependents: Vec<(String, Vec<DeviceProtocols>, Arc<Mutex<PollState>>)> =
+            dependents(&devices, &tags_to_read)
+                .into_iter()
+                .map(|(name, tags)| {
+                    let state = virtual_states.entry(name.to_owned()).or_insert_with(|| {
+                        let state = Arc::new(Mutex::new(PollState::default()));
+                        let virtual_tags: Vec<DeviceProtocols> = devices
+                            .iter()
+                            .filter(|dev| dev.device_name() == name)
+                            .cloned()
+                            .collect();
+                        close_windows_on_time(
+                            name.to_owned(),
+                            &virtual_tags,
+                            state.to_owned(),
+                            topics.to_owned(),
+                            send_f.to_owned(),
+                        );
+                        state
+                    });
+                    (name, tags, state.to_owned())
+                })
+                .collect();
 
         let job = Job::new_repeated_async(Duration::from_secs(seconds), move |_uuid, _l| {
             let device_name = device_name.to_owned();
             let tags_to_read = tags_to_read.to_owned();
-            let send_f = (&send_f).to_owned();
-            Box::pin(async move {
-                let json = job_function(&tags_to_read).await;
-                send_f(&device_name, &json).unwrap();
-            })
+            let send_f = send_f.to_owned();
+            let topics = topics.to_owned();
+            let state = state.to_owned();
+            let dependents = dependents.to_owned();
+            let span = tracing::info_span!("poll", device = %device_name);
+            let job = async move {
+                let started = Instant::now();
+                let result = job_function(&tags_to_read, &state).await;
+                let elapsed = started.elapsed();
+                tracing::debug!(
+                    tags = tags_to_read.len(),
+                    duration_ms = elapsed.as_millis() as u64,
+                    "Poll done"
+                );
+                if elapsed > Duration::from_secs(seconds) {
+                    tracing::warn!(
+                        duration_ms = elapsed.as_millis() as u64,
+                        "The poll took longer than the read frequency"
+                    );
+                    metrics::record_poll_overrun(&device_name);
+                }
+                send_values(&send_f, &topics, &device_name, result);
+                for (virtual_device, tags, state) in dependents.iter() {
+                    let result = job_function(tags, state).await;
+                    send_values(&send_f, &topics, virtual_device, result);
+                }
+
+                // All the tags of the device share its state.
+                let state = tags_to_read.first().and_then(|dev| dev.take_state_change());
+                if let Some(state) = state {
+                    tracing::info!(state = ?state, "Device state changed");
+                    stream::publish(StreamEvent::Status {
+                        device: device_name.to_owned(),
+                        timestamp: chrono::Utc::now().to_rfc3339(),
+                        state,
+                    });
+                    let status = serde_json::json!({ "device": device_name, "state": state });
+                    if let Err(err) = send_f(&topics.status(&device_name, &status)) {
+                        tracing::error!(error = %err, "The status cannot be sent");
+                    }
+                }
+            };
+            Box::pin(job.instrument(span))
         });
         sched.add(job.unwrap()).await.unwrap();
     }
@@ -83,18 +309,21 @@ pub async fn tag_one_shot_read(
 ) -> String {
     let error_msg: String = "Error".to_string();
 
-    let device = devices.iter().find(|dev| dev.tag_name() == tag_to_read);
+    // The tag is `device/tag`, or only its name when no other device has it.
+    let found: Vec<&DeviceProtocols> = devices
+        .iter()
+        .filter(|dev| dev.id() == tag_to_read || dev.tag_name() == tag_to_read)
+        .collect();
+    let device = match found.as_slice() {
+        [device] => Some(*device),
+        _ => None,
+    };
 
     if let Some(device) = device {
         let mut retries = retries;
 
         while retries > 0 {
-            if let Ok(Ok(x)) = tokio::time::timeout(
-                Duration::new(TAG_REQUEST_SECONDS_TO_TIMEOUT, 0),
-                device.read(),
-            )
-            .await
-            {
+            if let Ok(x) = device.read(Priority::Command).await {
                 return x.value.to_string();
             }
             retries -= 1;